@@ -0,0 +1,87 @@
+use std::time::{Duration, Instant};
+use macocr::{get_ocr_results_from_bytes, warm_up, OCROptions};
+use crate::{read_input, report_failure, Args, InputFile};
+
+// Latencies of OCR runs and the characters recognized by all of them
+struct Timings {
+    latencies: Vec<Duration>,
+    chars: usize,
+}
+
+impl Timings {
+    fn report(&self, name: &str) {
+        let mut latencies = self.latencies.clone();
+        latencies.sort();
+        let total: Duration = latencies.iter().sum();
+        let chars_per_second = self.chars as f64 / total.as_secs_f64();
+        eprintln!(
+            "{}: {} runs, min {:.1} ms, median {:.1} ms, max {:.1} ms, {:.0} chars/s",
+            name,
+            latencies.len(),
+            millis(latencies[0]),
+            millis(median(&latencies)),
+            millis(latencies[latencies.len() - 1]),
+            chars_per_second
+        );
+    }
+}
+
+// OCR every input `runs` times and report its latencies on stderr, returning whether any input failed.
+// The inputs are read before timing, so only the OCR is measured.
+pub fn run_benchmark(files: &[InputFile], runs: u16, args: &Args, options: &OCROptions) -> bool {
+    let mut failed = false;
+    let mut all = Timings { latencies: Vec::new(), chars: 0 };
+    // Vision loads its recognition model on the first OCR, which would skew the first run
+    warm_up(options);
+
+    for input in files {
+        let file = input.path.as_str();
+        let bytes = match read_input(file, Duration::from_secs(args.timeout)) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                failed |= report_failure(args, file, &e.into());
+                continue;
+            }
+        };
+
+        let mut timings = Timings { latencies: Vec::new(), chars: 0 };
+        for _ in 0..runs {
+            let start = Instant::now();
+            match get_ocr_results_from_bytes(bytes.clone(), args.frame, options) {
+                Ok(ocr_results) => {
+                    timings.latencies.push(start.elapsed());
+                    timings.chars += ocr_results.iter().map(|r| r.text.chars().count()).sum::<usize>();
+                }
+                Err(e) => {
+                    failed |= report_failure(args, file, &e);
+                    break;
+                }
+            }
+        }
+        if timings.latencies.is_empty() {
+            continue;
+        }
+
+        timings.report(file);
+        all.chars += timings.chars;
+        all.latencies.extend(timings.latencies);
+    }
+
+    if files.len() > 1 && !all.latencies.is_empty() {
+        all.report("all files");
+    }
+    failed
+}
+
+fn median(sorted: &[Duration]) -> Duration {
+    let middle = sorted.len() / 2;
+    if sorted.len().is_multiple_of(2) {
+        (sorted[middle - 1] + sorted[middle]) / 2
+    } else {
+        sorted[middle]
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
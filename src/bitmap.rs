@@ -0,0 +1,27 @@
+use image::{ImageFormat, RgbaImage};
+use objc2_core_graphics::{CGBitmapContextCreate, CGColorSpace, CGContext, CGImageAlphaInfo};
+use std::io::Cursor;
+
+// Draw into an RGBA bitmap context on an opaque white background and encode the result as PNG
+pub fn render_png(width: usize, height: usize, draw: impl FnOnce(&CGContext)) -> Option<Vec<u8>> {
+    if width == 0 || height == 0 {
+        return None;
+    }
+
+    // Opaque white, so transparent areas don't end up black
+    let mut pixels = vec![255u8; width * height * 4];
+    let color_space = unsafe { CGColorSpace::new_device_rgb() }?;
+    let context = unsafe {
+        CGBitmapContextCreate(
+            pixels.as_mut_ptr().cast(), width, height, 8, width * 4,
+            Some(&color_space), CGImageAlphaInfo::PremultipliedLast.0
+        )
+    }?;
+    draw(&context);
+    drop(context);
+
+    let image = RgbaImage::from_raw(width as u32, height as u32, pixels)?;
+    let mut png = Vec::new();
+    image.write_to(&mut Cursor::new(&mut png), ImageFormat::Png).ok()?;
+    Some(png)
+}
@@ -0,0 +1,47 @@
+use std::{fs, io, path::{Path, PathBuf}};
+use macocr::{OCROptions, OCRResult, VERSION};
+use uuid::Uuid;
+
+// Directory of OCR results stored as JSON, keyed by a hash of the input bytes and the settings
+#[derive(Clone)]
+pub struct Cache {
+    dir: PathBuf,
+    // every option (and the version) changing the results, so changing any of them misses the cache
+    settings: String,
+}
+
+impl Cache {
+    pub fn new(dir: &Path, options: &OCROptions, frame: Option<usize>) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        // Retries don't change the results of a successful OCR
+        let options = OCROptions { retries: 0, ..options.clone() };
+        Ok(Cache {
+            dir: dir.to_path_buf(),
+            settings: format!("{} {:?} {:?}", VERSION, frame, options),
+        })
+    }
+
+    // The cached results of an input, None when they aren't cached (or can't be read)
+    pub fn get(&self, bytes: &[u8]) -> Option<Vec<OCRResult>> {
+        let json = fs::read(self.path(bytes)).ok()?;
+        serde_json::from_slice(&json).ok()
+    }
+
+    // Store the results of an input. They're written to a temporary file renamed into place,
+    // so concurrent runs never read a partial entry.
+    pub fn put(&self, bytes: &[u8], ocr_results: &[OCRResult]) -> io::Result<()> {
+        let path = self.path(bytes);
+        let temp_path = path.with_extension(format!("{}.tmp", Uuid::new_v4()));
+        fs::write(&temp_path, serde_json::to_vec(ocr_results)?)?;
+        fs::rename(&temp_path, &path).inspect_err(|_| {
+            let _ = fs::remove_file(&temp_path);
+        })
+    }
+
+    fn path(&self, bytes: &[u8]) -> PathBuf {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(self.settings.as_bytes());
+        hasher.update(bytes);
+        self.dir.join(format!("{}.json", hasher.finalize().to_hex()))
+    }
+}
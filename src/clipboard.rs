@@ -0,0 +1,18 @@
+use objc2_app_kit::{NSPasteboard, NSPasteboardTypePNG, NSPasteboardTypeString, NSPasteboardTypeTIFF};
+use objc2_foundation::NSString;
+
+// Read the image on the general pasteboard, preferring PNG over TIFF (e.g. from ⌘⇧4 screenshots)
+pub fn read_image() -> Option<Vec<u8>> {
+    let pasteboard = NSPasteboard::generalPasteboard();
+    let types = unsafe { [NSPasteboardTypePNG, NSPasteboardTypeTIFF] };
+    types.iter()
+        .find_map(|data_type| pasteboard.dataForType(data_type))
+        .map(|data| data.to_vec())
+}
+
+// Replace the contents of the general pasteboard with a text, returning whether it was written
+pub fn write_text(text: &str) -> bool {
+    let pasteboard = NSPasteboard::generalPasteboard();
+    pasteboard.clearContents();
+    pasteboard.setString_forType(&NSString::from_str(text), unsafe { NSPasteboardTypeString })
+}
@@ -0,0 +1,68 @@
+use objc2_core_foundation::{CFBoolean, CFData, CFDictionary, CFNumber, CFRetained, CFString, CFType, CGPoint, CGRect, CGSize};
+use objc2_core_graphics::{CGContext, CGImage};
+use objc2_image_io::{
+    kCGImagePropertyDPIWidth, kCGImageSourceCreateThumbnailFromImageAlways, kCGImageSourceCreateThumbnailWithTransform,
+    CGImageSource,
+};
+use crate::bitmap::render_png;
+
+pub fn is_tiff(bytes: &[u8]) -> bool {
+    infer::image::is_tiff(bytes)
+}
+
+pub fn is_heif(bytes: &[u8]) -> bool {
+    infer::image::is_heif(bytes)
+}
+
+// Decode the pages of a multi-page TIFF with ImageIO and re-encode each one as PNG.
+// The image crate only decodes the first page, so single-page TIFFs return None.
+pub fn decode_tiff_pages(bytes: &[u8]) -> Option<Vec<Vec<u8>>> {
+    let source = image_source(bytes)?;
+    let page_count = unsafe { source.count() };
+    if page_count < 2 {
+        return None;
+    }
+
+    (0..page_count)
+        .map(|index| {
+            let image = unsafe { source.image_at_index(index, None) }?;
+            render_image(&image)
+        })
+        .collect()
+}
+
+// Decode an image the image crate can't read (HEIC/HEIF) with ImageIO and re-encode it as PNG.
+// A full-size "thumbnail" is created instead of the image itself, since only thumbnails can have
+// the EXIF orientation applied, otherwise portrait photos would be OCR'd sideways.
+pub fn decode_image(bytes: &[u8]) -> Option<Vec<u8>> {
+    let source = image_source(bytes)?;
+    let keys = unsafe { [kCGImageSourceCreateThumbnailFromImageAlways, kCGImageSourceCreateThumbnailWithTransform] };
+    let options = CFDictionary::<CFString, CFBoolean>::from_slices(&keys, &[CFBoolean::new(true), CFBoolean::new(true)]);
+    let image = unsafe { source.thumbnail_at_index(source.primary_image_index(), Some(options.as_opaque())) }?;
+    render_image(&image)
+}
+
+// Horizontal resolution recorded in the image metadata (e.g. the pHYs chunk of a PNG or the
+// density of a JPEG), None when the image doesn't record one
+pub fn dpi(bytes: &[u8]) -> Option<u32> {
+    let source = image_source(bytes)?;
+    let properties = unsafe { source.properties_at_index(source.primary_image_index(), None) }?;
+    // SAFETY: the image properties dictionary is keyed by strings
+    let properties = unsafe { CFRetained::cast_unchecked::<CFDictionary<CFString, CFType>>(properties) };
+    let dpi = properties.get(unsafe { kCGImagePropertyDPIWidth })?.downcast::<CFNumber>().ok()?.as_f64()?;
+    (dpi > 0.0).then(|| dpi.round() as u32)
+}
+
+fn image_source(bytes: &[u8]) -> Option<CFRetained<CGImageSource>> {
+    let data = CFData::from_bytes(bytes);
+    unsafe { CGImageSource::with_data(&data, None) }
+}
+
+fn render_image(image: &CGImage) -> Option<Vec<u8>> {
+    let width = unsafe { CGImage::width(Some(image)) };
+    let height = unsafe { CGImage::height(Some(image)) };
+    let rect = CGRect::new(CGPoint::new(0.0, 0.0), CGSize::new(width as f64, height as f64));
+    render_png(width, height, |context| unsafe {
+        CGContext::draw_image(Some(context), rect, Some(image));
+    })
+}
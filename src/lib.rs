@@ -0,0 +1,1258 @@
+//! OCR with Apple's Vision Framework API.
+//!
+//! The `macocr` binary is built on top of this library, which can also be used
+//! directly to OCR images from Rust:
+//!
+//! ```no_run
+//! let bytes = std::fs::read("01.png").unwrap();
+//! let result = macocr::ocr_image_bytes(&bytes).unwrap();
+//! println!("{}", result.text);
+//! ```
+
+use clap::ValueEnum;
+use image::{AnimationDecoder, DynamicImage, ImageDecoder, ImageFormat, ImageReader, Rgba, RgbaImage, codecs::{gif::GifDecoder, webp::WebPDecoder}, imageops::FilterType, metadata::Orientation};
+use infer::{self, MatcherType};
+use std::{cell::RefCell, fmt, fs, io::{self, Cursor}, thread, time::Duration};
+use objc2::rc::Retained;
+use imageproc::contrast::{adaptive_threshold, otsu_level, threshold, ThresholdType};
+use imageproc::geometric_transformations::{warp_into, Interpolation, Projection};
+use objc2::{msg_send, AnyThread, ClassType};
+use objc2::runtime::AnyObject;
+use objc2_vision::{
+    VNDetectBarcodesRequest, VNDetectDocumentSegmentationRequest, VNImageRequestHandler, VNRecognizeTextRequest, 
+    VNRecognizeTextRequestRevision3, VNRectangleObservation, VNRequest, 
+    VNRequestTextRecognitionLevel
+};
+use objc2_foundation::{
+    NSArray, NSData, NSDictionary, NSIndexSet, NSNotFound, NSString
+};
+use objc2_image_io::CGImagePropertyOrientation;
+use objc2_natural_language::NLLanguageRecognizer;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use core_graphics::geometry::CGPoint;
+
+mod bitmap;
+pub mod output;
+mod image_io;
+mod pdf;
+
+/// app version
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+/// maximum width/height of an image enlarged with `OCROptions::upscale`
+pub const MAX_UPSCALED_DIMENSION: u32 = 8192;
+
+// width and height of the blank image OCR'd by `warm_up`
+const WARM_UP_IMAGE_SIZE: u32 = 64;
+
+// wait before the first retry of a failed Vision request, doubled for every further retry
+const RETRY_BACKOFF: Duration = Duration::from_millis(100);
+
+// image types (infer extensions) that ImageIO can decode for Vision
+const SUPPORTED_IMAGE_TYPES: [&str; 13] = [
+    "jpg", "jpx", "png", "gif", "webp", "cr2", "tif", "bmp", "psd", "ico", "heif", "avif", "jxl"
+];
+
+/// Vision text recognition level
+#[derive(ValueEnum, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RecognitionLevel {
+    /// Faster, less accurate recognition
+    Fast,
+    /// Slower, more accurate recognition
+    #[default]
+    Accurate,
+}
+
+impl RecognitionLevel {
+    fn to_vision(self) -> VNRequestTextRecognitionLevel {
+        match self {
+            RecognitionLevel::Fast => VNRequestTextRecognitionLevel::Fast,
+            RecognitionLevel::Accurate => VNRequestTextRecognitionLevel::Accurate,
+        }
+    }
+}
+
+/// Image preprocessing applied before OCR
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum Preprocess {
+    /// Convert to grayscale
+    Grayscale,
+    /// Binarize with a global threshold (Otsu's method)
+    Threshold,
+    /// Binarize with a threshold adapted to the local brightness, for uneven lighting
+    Adaptive,
+}
+
+impl Preprocess {
+    fn apply(self, img: DynamicImage) -> DynamicImage {
+        let gray = img.to_luma8();
+        let gray = match self {
+            Preprocess::Grayscale => gray,
+            Preprocess::Threshold => threshold(&gray, otsu_level(&gray), ThresholdType::Binary),
+            Preprocess::Adaptive => {
+                let block_radius = (gray.width().min(gray.height()) / 32).max(1);
+                adaptive_threshold(&gray, block_radius)
+            }
+        };
+        DynamicImage::ImageLuma8(gray)
+    }
+}
+
+/// OCR options shared by the CLI and the HTTP server
+#[derive(Clone, Debug, Default)]
+pub struct OCROptions {
+    pub input_rotate: u32,
+    pub dedup_boxes: Option<f64>,
+    pub dedup_lines: bool,
+    /// merge the lines into paragraph blocks, with this maximum vertical gap in line heights
+    pub paragraphs: Option<f64>,
+    pub min_confidence: f32,
+    pub candidates: usize,
+    pub min_text_height: f32,
+    pub custom_words: Vec<String>,
+    pub with_direction: bool,
+    pub with_quad: bool,
+    pub normalized: bool,
+    pub upscale: f64,
+    pub preprocess: Option<Preprocess>,
+    pub detect_line_language: bool,
+    pub keep_image: bool,
+    pub layout: bool,
+    pub dehyphenate: bool,
+    /// regex substitutions applied in order to the recognized text
+    pub replacements: Vec<(Regex, String)>,
+    /// keep only the lines of the text matching this regex
+    pub grep: Option<Regex>,
+    /// with `grep`, keep only the boxes matching it as well
+    pub grep_boxes: bool,
+    /// recognition languages in priority order; Vision prefers them, but still detects the language
+    /// automatically unless `no_auto_language` is set (setting `languages` alone used to turn it off)
+    pub languages: Vec<String>,
+    /// turn off Vision's automatic language detection, so it only recognizes `languages`
+    pub no_auto_language: bool,
+    /// fill `OCRResult::detected_languages` (unless `no_auto_language` is set), e.g. for JSON output
+    pub report_languages: bool,
+    pub level: RecognitionLevel,
+    /// Vision text recognition request revision, `None` for revision 3
+    pub revision: Option<usize>,
+    /// times a failed Vision request is retried before giving up
+    pub retries: u32,
+    /// minimum width and height (in px) of an image to OCR, smaller images fail with
+    /// `OcrError::ImageTooSmall` (0 for no minimum)
+    pub min_dimension: u32,
+    /// detect barcodes and QR codes along with the text
+    pub barcodes: bool,
+    /// detect the document (page) in the image and OCR it flattened, without the background
+    pub detect_document: bool,
+}
+
+/// Errors returned by the OCR functions
+#[derive(Debug)]
+pub enum OcrError {
+    /// Reading the input failed
+    Io(io::Error),
+    /// The input isn't an image
+    NotAnImage,
+    /// The input is an image of a type ImageIO can't decode for Vision, e.g. "jxr"
+    UnsupportedImageType(String),
+    /// The image couldn't be decoded or re-encoded
+    DecodeFailed,
+    /// Vision failed to perform the text recognition request
+    VisionFailed(String),
+    /// The image (width, height) is smaller than `OCROptions::min_dimension`
+    ImageTooSmall(u32, u32),
+    /// The requested page/frame index is past the last of the input's `count` pages/frames
+    FrameOutOfRange { frame: usize, count: usize },
+}
+
+impl fmt::Display for OcrError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OcrError::Io(e) => write!(f, "{}", e),
+            OcrError::NotAnImage => write!(f, "The file type is not an image"),
+            OcrError::UnsupportedImageType(image_type) => write!(f, "Unsupported image type: {}", image_type),
+            OcrError::DecodeFailed => write!(f, "Failed to decode the image"),
+            OcrError::VisionFailed(description) => write!(f, "Text recognition failed: {}", description),
+            OcrError::ImageTooSmall(width, height) => write!(f, "The image is too small to OCR: {}x{} px", width, height),
+            OcrError::FrameOutOfRange { frame, count } => write!(f, "Frame {} is out of range, the input has {} frame(s)", frame, count),
+        }
+    }
+}
+
+impl std::error::Error for OcrError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            OcrError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for OcrError {
+    fn from(e: io::Error) -> Self {
+        OcrError::Io(e)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct OCRResult {
+    pub text: String,
+    pub image_width: u32,
+    pub image_height: u32,
+    pub boxes: Vec<OCRBoxItem>,
+    /// languages detected in the text with `OCROptions::report_languages` when Vision detects the
+    /// language automatically, most used first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub detected_languages: Vec<String>,
+    /// barcodes and QR codes detected with `OCROptions::barcodes`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub barcodes: Vec<OCRBarcodeItem>,
+    /// corners of the document detected with `OCROptions::detect_document` as [x, y] pixel pairs
+    /// (top-left, top-right, bottom-right, bottom-left) in the (rotated) input image. The boxes are then
+    /// positioned in the flattened document, which is `image_width` × `image_height`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub document: Option<[[f64; 2]; 4]>,
+    /// type of the input (infer extension, e.g. "png" or "pdf")
+    pub format: String,
+    /// resolution of the input in dots per inch, when its metadata records it (or the render
+    /// resolution of PDF pages)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dpi: Option<u32>,
+    /// the (rotated) image bytes that were OCR'd, kept only when an output needs them
+    #[serde(skip)]
+    pub image: Option<Vec<u8>>
+}
+
+impl OCRResult {
+    pub fn new(text: String, image_width: u32, image_height: u32, boxes: Vec<OCRBoxItem>) -> Self {
+        OCRResult {
+            text,
+            image_width,
+            image_height,
+            boxes,
+            detected_languages: Vec::new(),
+            barcodes: Vec::new(),
+            document: None,
+            format: String::new(),
+            dpi: None,
+            image: None,
+        }
+    }
+
+    /// Languages of the boxes (reusing their `language` when it's already detected), ordered by
+    /// the number of characters written in them
+    pub fn language_report(&self) -> Vec<String> {
+        let mut counts: Vec<(String, usize)> = Vec::new();
+        for item in &self.boxes {
+            let Some(language) = item.language.clone().or_else(|| detect_language(&item.text)) else {
+                continue;
+            };
+            let chars = item.text.chars().count();
+            match counts.iter_mut().find(|(known, _)| *known == language) {
+                Some((_, count)) => *count += chars,
+                None => counts.push((language, chars)),
+            }
+        }
+        counts.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+        counts.into_iter().map(|(language, _)| language).collect()
+    }
+
+    /// Keep only the boxes matching the predicate and rebuild the text from them
+    pub fn retain_boxes<F: FnMut(&OCRBoxItem) -> bool>(&mut self, f: F) {
+        self.boxes.retain(f);
+        self.text = self.boxes.iter().map(|item| format!("{}\n", item.text)).collect();
+    }
+
+    /// Drop boxes overlapping a higher-confidence box by more than the IoU threshold
+    pub fn dedup_boxes(&mut self, iou_threshold: f64) {
+        let mut order: Vec<usize> = (0..self.boxes.len()).collect();
+        order.sort_by(|&a, &b| self.boxes[b].confidence.total_cmp(&self.boxes[a].confidence));
+
+        let mut keep = vec![false; self.boxes.len()];
+        let mut kept: Vec<usize> = Vec::new();
+        for i in order {
+            if kept.iter().all(|&k| self.boxes[i].iou(&self.boxes[k]) <= iou_threshold) {
+                keep[i] = true;
+                kept.push(i);
+            }
+        }
+
+        let mut keep = keep.into_iter();
+        self.retain_boxes(|_| keep.next().unwrap_or(false));
+    }
+
+    /// Drop lines repeating the previous line, e.g. from overlapping screenshots stitched together
+    pub fn dedup_lines(&mut self) {
+        let mut previous: Option<String> = None;
+        self.retain_boxes(|item| {
+            let line = item.text.trim();
+            let repeated = previous.as_deref() == Some(line);
+            previous = Some(line.to_string());
+            !repeated
+        });
+    }
+
+    /// Merge the boxes into paragraph blocks. A box joins the paragraph above it when their
+    /// horizontal ranges overlap and the vertical gap between them is at most `gap` times the
+    /// box height. A block has the text of its lines joined with spaces, the union of their
+    /// rects and the lowest confidence of its lines.
+    pub fn merge_paragraphs(&mut self, gap: f64) {
+        let mut order: Vec<OCRBoxItem> = std::mem::take(&mut self.boxes);
+        order.sort_by(|a, b| a.y.total_cmp(&b.y));
+
+        let mut paragraphs: Vec<Vec<OCRBoxItem>> = Vec::new();
+        for item in order {
+            // The closest paragraph above, as the last one pushed has the lowest top
+            let paragraph = paragraphs.iter_mut().rev().find(|lines| {
+                let (left, right) = lines.iter().fold((f64::INFINITY, f64::NEG_INFINITY), |(left, right), line| {
+                    (left.min(line.x), right.max(line.x + line.w))
+                });
+                let bottom = lines.iter().map(|line| line.y + line.h).fold(f64::NEG_INFINITY, f64::max);
+                item.x < right && item.x + item.w > left && item.y - bottom <= gap * item.h
+            });
+            match paragraph {
+                Some(lines) => lines.push(item),
+                None => paragraphs.push(vec![item]),
+            }
+        }
+
+        self.boxes = paragraphs.into_iter().map(OCRBoxItem::merge).collect();
+        self.retain_boxes(|_| true);
+    }
+
+    /// Rebuild the text with the boxes' approximate spatial layout. Boxes are grouped into
+    /// lines by vertical overlap and lines are ordered by y. Every box is padded with spaces
+    /// to the column given by its x offset from the leftmost box, in average character widths.
+    pub fn layout_text(&self) -> String {
+        let chars: usize = self.boxes.iter().map(|item| item.text.chars().count()).sum();
+        let widths: f64 = self.boxes.iter().map(|item| item.w).sum();
+        if chars == 0 || widths <= 0.0 {
+            return self.text.clone();
+        }
+        let char_width = widths / chars as f64;
+        let left = self.boxes.iter().map(|item| item.x).fold(f64::INFINITY, f64::min);
+
+        let mut text = String::new();
+        for items in self.visual_lines() {
+            let mut line = String::new();
+            let mut column = 0;
+            for item in items {
+                let target = ((item.x - left) / char_width).round() as usize;
+                let padding = if column == 0 { target } else { target.saturating_sub(column).max(1) };
+                line.push_str(&" ".repeat(padding));
+                line.push_str(&item.text);
+                column += padding + item.text.chars().count();
+            }
+            text.push_str(&line);
+            text.push('\n');
+        }
+        text
+    }
+
+    /// Detect the table in the boxes, as a rows × columns grid of cell texts. The rows are the
+    /// visual lines; the columns are the clusters of overlapping horizontal ranges of the boxes
+    /// in the rows with several cells, so a title spanning the table doesn't merge them. Every
+    /// box goes to the nearest column, the boxes of a cell are joined with spaces and the
+    /// empty cells are empty strings.
+    pub fn table(&self) -> Vec<Vec<String>> {
+        let lines = self.visual_lines();
+        let mut ranges: Vec<(f64, f64)> = lines.iter()
+            .filter(|items| items.len() > 1)
+            .flatten()
+            .map(|item| (item.x, item.x + item.w))
+            .collect();
+        if ranges.is_empty() {
+            ranges = self.boxes.iter().map(|item| (item.x, item.x + item.w)).collect();
+        }
+        ranges.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+        let mut columns: Vec<(f64, f64)> = Vec::new();
+        for (left, right) in ranges {
+            match columns.last_mut() {
+                Some((_, column_right)) if left < *column_right => *column_right = column_right.max(right),
+                _ => columns.push((left, right)),
+            }
+        }
+
+        lines.into_iter()
+            .map(|items| {
+                let mut row = vec![String::new(); columns.len()];
+                for item in items {
+                    let distance = |&(left, right): &(f64, f64)| (left - (item.x + item.w)).max(item.x - right).max(0.0);
+                    let column = (0..columns.len())
+                        .min_by(|&a, &b| distance(&columns[a]).total_cmp(&distance(&columns[b])))
+                        .unwrap_or(0);
+                    if !row[column].is_empty() {
+                        row[column].push(' ');
+                    }
+                    row[column].push_str(item.text.trim());
+                }
+                row
+            })
+            .collect()
+    }
+
+    // Group the boxes into visual lines ordered by y, each ordered by x. A box belongs to the
+    // current line when its vertical center lies within the line's extent.
+    fn visual_lines(&self) -> Vec<Vec<&OCRBoxItem>> {
+        let mut order: Vec<&OCRBoxItem> = self.boxes.iter().collect();
+        order.sort_by(|a, b| (a.y + a.h / 2.0).total_cmp(&(b.y + b.h / 2.0)));
+
+        let mut lines: Vec<(f64, f64, Vec<&OCRBoxItem>)> = Vec::new();
+        for item in order {
+            let center = item.y + item.h / 2.0;
+            match lines.last_mut() {
+                Some((top, bottom, items)) if center >= *top && center <= *bottom => {
+                    *top = top.min(item.y);
+                    *bottom = bottom.max(item.y + item.h);
+                    items.push(item);
+                }
+                _ => lines.push((item.y, item.y + item.h, vec![item])),
+            }
+        }
+
+        lines.into_iter()
+            .map(|(_, _, mut items)| {
+                items.sort_by(|a, b| a.x.total_cmp(&b.x));
+                items
+            })
+            .collect()
+    }
+
+    /// Convert the box coordinates from pixels to fractions (0.0-1.0) of the image size.
+    /// The origin stays at the top-left corner of the image, with y growing downward.
+    pub fn normalize_boxes(&mut self) {
+        let (width, height) = (self.image_width as f64, self.image_height as f64);
+        for item in &mut self.boxes {
+            item.scale(1.0 / width, 1.0 / height);
+        }
+        for barcode in &mut self.barcodes {
+            barcode.scale(1.0 / width, 1.0 / height);
+        }
+    }
+
+    /// Remove non-printable control characters from the text and box text
+    pub fn strip_control_chars(&mut self) {
+        self.text = strip_control_chars(&self.text);
+        for item in &mut self.boxes {
+            item.text = strip_control_chars(&item.text);
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct OCRBoxItem {
+    pub text: String,
+    pub x: f64,
+    pub y: f64,
+    pub w: f64,
+    pub h: f64,
+    pub rect: OCRRectItem,
+    pub confidence: f32,
+    /// rotation of the text baseline in degrees, counterclockwise (the page skew for scanned documents)
+    pub angle: f64,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub alternatives: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub direction: Option<TextDirection>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
+    /// the four corners (top-left, top-right, bottom-right, bottom-left) as [x, y] pixel pairs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quad: Option<[[f64; 2]; 4]>
+}
+
+impl OCRBoxItem {
+    pub fn new(text: String, x: f64, y: f64, w: f64, h: f64, rect: OCRRectItem, confidence: f32) -> Self {
+        let angle = rect.angle();
+        OCRBoxItem { text, x, y, w, h, rect, confidence, angle, alternatives: Vec::new(), direction: None, language: None, quad: None }
+    }
+
+    /// Scale all the coordinates of the box. The angle stays the one in pixels.
+    pub fn scale(&mut self, sx: f64, sy: f64) {
+        self.x *= sx;
+        self.y *= sy;
+        self.w *= sx;
+        self.h *= sy;
+        self.rect.scale(sx, sy);
+        if let Some(quad) = &mut self.quad {
+            for point in quad {
+                point[0] *= sx;
+                point[1] *= sy;
+            }
+        }
+    }
+
+    // Merge the lines of a paragraph into one box spanning all of them
+    fn merge(lines: Vec<OCRBoxItem>) -> OCRBoxItem {
+        let left = lines.iter().map(|line| line.x).fold(f64::INFINITY, f64::min);
+        let top = lines.iter().map(|line| line.y).fold(f64::INFINITY, f64::min);
+        let right = lines.iter().map(|line| line.x + line.w).fold(f64::NEG_INFINITY, f64::max);
+        let bottom = lines.iter().map(|line| line.y + line.h).fold(f64::NEG_INFINITY, f64::max);
+        let confidence = lines.iter().map(|line| line.confidence).fold(f32::INFINITY, f32::min);
+        let text = lines.iter().map(|line| line.text.trim()).collect::<Vec<_>>().join(" ");
+
+        let rect = OCRRectItem::new(left, top, right, top, right, bottom, left, bottom);
+        OCRBoxItem::new(text, left, top, right - left, bottom - top, rect, confidence)
+    }
+
+    // Intersection over union of the axis-aligned rects
+    fn iou(&self, other: &OCRBoxItem) -> f64 {
+        let w = (self.x + self.w).min(other.x + other.w) - self.x.max(other.x);
+        let h = (self.y + self.h).min(other.y + other.h) - self.y.max(other.y);
+        if w <= 0.0 || h <= 0.0 {
+            return 0.0;
+        }
+        let intersection = w * h;
+        intersection / (self.w * self.h + other.w * other.h - intersection)
+    }
+}
+
+/// Barcode or QR code detected in the image
+#[derive(Serialize, Deserialize)]
+pub struct OCRBarcodeItem {
+    /// Vision symbology identifier, e.g. "VNBarcodeSymbologyQR"
+    pub symbology: String,
+    /// the payload as a string, when it has one
+    pub payload: Option<String>,
+    pub x: f64,
+    pub y: f64,
+    pub w: f64,
+    pub h: f64,
+    pub rect: OCRRectItem,
+    pub confidence: f32,
+}
+
+impl OCRBarcodeItem {
+    /// Scale all the coordinates of the barcode
+    pub fn scale(&mut self, sx: f64, sy: f64) {
+        self.x *= sx;
+        self.y *= sy;
+        self.w *= sx;
+        self.h *= sy;
+        self.rect.scale(sx, sy);
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct OCRRectItem {
+    pub top_left_x: f64,
+    pub top_left_y: f64,
+    pub top_right_x: f64,
+    pub top_right_y: f64,
+    pub bottom_right_x: f64,
+    pub bottom_right_y: f64,
+    pub bottom_left_x: f64,
+    pub bottom_left_y: f64,
+}
+
+impl OCRRectItem {
+    pub fn new(top_left_x: f64, top_left_y: f64, top_right_x: f64, top_right_y: f64, 
+           bottom_right_x: f64, bottom_right_y: f64, bottom_left_x: f64, bottom_left_y: f64) -> Self {
+        OCRRectItem { top_left_x, top_left_y, top_right_x, top_right_y, 
+                      bottom_right_x, bottom_right_y, bottom_left_x, bottom_left_y }
+    }
+
+    /// Scale all the corner coordinates
+    pub fn scale(&mut self, sx: f64, sy: f64) {
+        self.top_left_x *= sx;
+        self.top_left_y *= sy;
+        self.top_right_x *= sx;
+        self.top_right_y *= sy;
+        self.bottom_right_x *= sx;
+        self.bottom_right_y *= sy;
+        self.bottom_left_x *= sx;
+        self.bottom_left_y *= sy;
+    }
+
+    /// Angle of the top edge (top-left to top-right) in degrees, counterclockwise from the
+    /// x axis. The y axis points down, so it's flipped to get the angle as seen on the image.
+    pub fn angle(&self) -> f64 {
+        let dx = self.top_right_x - self.top_left_x;
+        let dy = self.top_right_y - self.top_left_y;
+        (-dy).atan2(dx).to_degrees()
+    }
+
+    /// The corners as [x, y] pairs, clockwise from the top-left corner
+    pub fn quad(&self) -> [[f64; 2]; 4] {
+        [
+            [self.top_left_x, self.top_left_y],
+            [self.top_right_x, self.top_right_y],
+            [self.bottom_right_x, self.bottom_right_y],
+            [self.bottom_left_x, self.bottom_left_y],
+        ]
+    }
+}
+
+/// Base direction of a recognized line
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TextDirection {
+    Ltr,
+    Rtl,
+}
+
+impl TextDirection {
+    /// Infer the direction from the dominant script of the letters in the text
+    pub fn detect(text: &str) -> Self {
+        let (rtl, ltr) = text.chars()
+            .filter(|c| c.is_alphabetic())
+            .fold((0, 0), |(rtl, ltr), c| if is_rtl_char(c) { (rtl + 1, ltr) } else { (rtl, ltr + 1) });
+        if rtl > ltr { TextDirection::Rtl } else { TextDirection::Ltr }
+    }
+}
+
+// Hebrew, Arabic, Syriac, Thaana, NKo and the other right-to-left blocks
+fn is_rtl_char(c: char) -> bool {
+    matches!(c as u32,
+        0x0590..=0x08FF | 0xFB1D..=0xFDFF | 0xFE70..=0xFEFF | 0x10800..=0x10FFF | 0x1E800..=0x1EFFF)
+}
+
+pub fn is_image(path: &str) -> bool {
+    let data = fs::read(path);
+    match data {
+        Ok(data) => infer::is_image(&data),
+        Err(_) => false
+    }
+}
+
+/// Whether a file is an image or a PDF document
+pub fn is_ocr_input(path: &str) -> bool {
+    match fs::read(path) {
+        Ok(data) => infer::is_image(&data) || pdf::is_pdf(&data),
+        Err(_) => false
+    }
+}
+
+/// Sniff the file type of bytes from their content, as a file extension like "png" or "jpg"
+pub fn detect_file_type(bytes: &[u8]) -> Option<&'static str> {
+    infer::get(bytes).map(|kind| kind.extension())
+}
+
+/// Check that bytes are an image of a type that can be OCR'd
+pub fn check_image_type(bytes: &[u8]) -> Result<(), OcrError> {
+    match infer::get(bytes) {
+        Some(kind) if kind.matcher_type() == MatcherType::Image => {
+            if SUPPORTED_IMAGE_TYPES.contains(&kind.extension()) {
+                Ok(())
+            } else {
+                Err(OcrError::UnsupportedImageType(kind.extension().to_string()))
+            }
+        }
+        _ => Err(OcrError::NotAnImage),
+    }
+}
+
+pub fn get_ocr_result(path: &str, options: &OCROptions) -> Result<OCRResult, OcrError> {
+    let bytes = fs::read(path)?;
+    check_image_type(&bytes)?;
+    get_ocr_result_from_bytes(&bytes, options)
+}
+
+/// OCR an encoded image with the default options
+pub fn ocr_image_bytes(bytes: &[u8]) -> Result<OCRResult, OcrError> {
+    get_ocr_result_from_bytes(bytes, &OCROptions::default())
+}
+
+/// OCR each page of a PDF or multi-page TIFF, or each frame of an animated GIF/WebP
+/// (or the single image otherwise), optionally limited to one page/frame index
+pub fn get_ocr_results_from_bytes(bytes: Vec<u8>, frame: Option<usize>, options: &OCROptions) -> Result<Vec<OCRResult>, OcrError> {
+    if pdf::is_pdf(&bytes) {
+        check_frame(frame, pdf::page_count(&bytes).ok_or(OcrError::DecodeFailed)?)?;
+        let pages = pdf::render_pages(&bytes, frame).ok_or(OcrError::DecodeFailed)?;
+        return pages.iter()
+            .map(|page| {
+                let mut ocr_result = get_ocr_result_from_bytes(page, options)?;
+                ocr_result.format = "pdf".to_string();
+                ocr_result.dpi = Some(pdf::RENDER_DPI as u32);
+                Ok(ocr_result)
+            })
+            .collect();
+    }
+
+    check_image_type(&bytes)?;
+
+    let pages = if image_io::is_tiff(&bytes) { image_io::decode_tiff_pages(&bytes) } else { decode_animation_frames(&bytes) };
+    let Some(frames) = pages else {
+        check_frame(frame, 1)?;
+        return Ok(vec![get_ocr_result_from_bytes(&bytes, options)?]);
+    };
+    check_frame(frame, frames.len())?;
+
+    // The frames are re-encoded as PNG, so the format and resolution come from the input
+    let format = detect_file_type(&bytes).unwrap_or_default();
+    let dpi = image_io::dpi(&bytes);
+    frames.iter()
+        .enumerate()
+        .filter(|(index, _)| frame.is_none_or(|n| n == *index))
+        .map(|(_, data)| {
+            let mut ocr_result = get_ocr_result_from_bytes(data, options)?;
+            ocr_result.format = format.to_string();
+            ocr_result.dpi = dpi;
+            Ok(ocr_result)
+        })
+        .collect()
+}
+
+// Fail for a page/frame index past the last one, instead of OCR'ing nothing
+fn check_frame(frame: Option<usize>, count: usize) -> Result<(), OcrError> {
+    match frame {
+        Some(frame) if frame >= count => Err(OcrError::FrameOutOfRange { frame, count }),
+        _ => Ok(()),
+    }
+}
+
+// Decode the frames of an animated GIF/WebP and re-encode each one as PNG
+fn decode_animation_frames(bytes: &[u8]) -> Option<Vec<Vec<u8>>> {
+    let frames = match image::guess_format(bytes).ok()? {
+        ImageFormat::Gif => GifDecoder::new(Cursor::new(bytes)).ok()?.into_frames(),
+        ImageFormat::WebP => {
+            let decoder = WebPDecoder::new(Cursor::new(bytes)).ok()?;
+            if !decoder.has_animation() {
+                return None;
+            }
+            decoder.into_frames()
+        }
+        _ => return None,
+    };
+
+    let frames = frames.collect_frames().ok()?;
+    if frames.len() < 2 {
+        return None;
+    }
+
+    frames.into_iter()
+        .map(|frame| {
+            let mut png = Vec::new();
+            DynamicImage::ImageRgba8(frame.into_buffer())
+                .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+                .ok()?;
+            Some(png)
+        })
+        .collect()
+}
+
+// Settings of a text recognition request, to tell whether a reused request fits the options
+#[derive(PartialEq)]
+struct TextRequestSettings {
+    revision: Option<usize>,
+    level: RecognitionLevel,
+    min_text_height: f32,
+    languages: Vec<String>,
+    no_auto_language: bool,
+    custom_words: Vec<String>,
+}
+
+impl TextRequestSettings {
+    fn new(options: &OCROptions) -> Self {
+        TextRequestSettings {
+            revision: options.revision,
+            level: options.level,
+            min_text_height: options.min_text_height,
+            languages: options.languages.clone(),
+            no_auto_language: options.no_auto_language,
+            custom_words: options.custom_words.clone(),
+        }
+    }
+}
+
+thread_local! {
+    // Text request of the last OCR on this thread. A request can be performed again with a new
+    // handler once the previous one is done, but not concurrently, so each thread keeps its own.
+    static TEXT_REQUEST: RefCell<Option<(TextRequestSettings, Retained<VNRecognizeTextRequest>)>> = const { RefCell::new(None) };
+}
+
+// The text request of this thread, configured anew only when the options changed since the last OCR.
+// Reusing it saves creating and configuring a request for every image of a batch or the server.
+fn reusable_text_request(options: &OCROptions) -> Retained<VNRecognizeTextRequest> {
+    let settings = TextRequestSettings::new(options);
+    TEXT_REQUEST.with_borrow_mut(|reusable| match reusable {
+        Some((reusable_settings, request)) if *reusable_settings == settings => request.clone(),
+        _ => {
+            let request = new_text_request(options);
+            *reusable = Some((settings, request.clone()));
+            request
+        }
+    })
+}
+
+// Create a text recognition request configured from the OCR options
+fn new_text_request(options: &OCROptions) -> Retained<VNRecognizeTextRequest> {
+    let request = VNRecognizeTextRequest::new();
+    unsafe { request.setRevision(options.revision.unwrap_or(VNRecognizeTextRequestRevision3)) };
+    request.setRecognitionLevel(options.level.to_vision());
+    request.setUsesLanguageCorrection(true);
+    request.setMinimumTextHeight(options.min_text_height);
+
+    // With automatic detection, Vision picks the language of every image and the explicit languages
+    // are preferred. Without it, only the explicit languages are recognized, which avoids picking a
+    // wrong language for inputs whose languages are known.
+    if !options.languages.is_empty() {
+        request.setRecognitionLanguages(&ns_string_array(&options.languages));
+    }
+    request.setAutomaticallyDetectsLanguage(!options.no_auto_language);
+
+    // Custom words only take effect with language correction, which is always on
+    if !options.custom_words.is_empty() {
+        request.setCustomWords(&ns_string_array(&options.custom_words));
+    }
+
+    request
+}
+
+/// Recognition languages Vision supports for the configured level
+pub fn supported_languages(options: &OCROptions) -> Result<Vec<String>, String> {
+    let request = new_text_request(options);
+    match unsafe { request.supportedRecognitionLanguagesAndReturnError() } {
+        Ok(languages) => Ok(languages.iter().map(|language| language.to_string()).collect()),
+        Err(error) => Err(error.localizedDescription().to_string()),
+    }
+}
+
+/// Revision of the Vision text recognition request used for OCR
+pub fn recognition_revision() -> usize {
+    unsafe { new_text_request(&OCROptions::default()).revision() }
+}
+
+/// Text recognition request revisions supported by this macOS version, in ascending order
+pub fn supported_revisions() -> Vec<usize> {
+    // supportedRevisions is a class method, so it has to be sent to the VNRecognizeTextRequest class
+    let revisions: Retained<NSIndexSet> = unsafe { msg_send![VNRecognizeTextRequest::class(), supportedRevisions] };
+    let mut supported = Vec::new();
+    let mut revision = revisions.firstIndex();
+    while revision != NSNotFound as usize {
+        supported.push(revision);
+        revision = revisions.indexGreaterThanIndex(revision);
+    }
+    supported
+}
+
+fn ns_string_array(strings: &[String]) -> Retained<NSArray<NSString>> {
+    let strings: Vec<Retained<NSString>> = strings.iter().map(|s| NSString::from_str(s)).collect();
+    NSArray::from_retained_slice(&strings)
+}
+
+// EXIF orientation of an encoded image (NoTransforms when it has none)
+fn exif_orientation(bytes: &[u8]) -> Orientation {
+    ImageReader::new(Cursor::new(bytes)).with_guessed_format().ok()
+        .and_then(|reader| reader.into_decoder().ok())
+        .and_then(|mut decoder| decoder.orientation().ok())
+        .unwrap_or(Orientation::NoTransforms)
+}
+
+// ImageIO orientation with the same EXIF value
+fn cg_orientation(orientation: Orientation) -> CGImagePropertyOrientation {
+    match orientation {
+        Orientation::NoTransforms => CGImagePropertyOrientation::Up,
+        Orientation::FlipHorizontal => CGImagePropertyOrientation::UpMirrored,
+        Orientation::Rotate180 => CGImagePropertyOrientation::Down,
+        Orientation::FlipVertical => CGImagePropertyOrientation::DownMirrored,
+        Orientation::Rotate90FlipH => CGImagePropertyOrientation::LeftMirrored,
+        Orientation::Rotate90 => CGImagePropertyOrientation::Right,
+        Orientation::Rotate270FlipH => CGImagePropertyOrientation::RightMirrored,
+        Orientation::Rotate270 => CGImagePropertyOrientation::Left,
+    }
+}
+
+/// Decode an image as it's displayed, with its EXIF orientation applied
+pub fn load_oriented_image(bytes: &[u8]) -> Option<DynamicImage> {
+    let mut img = image::load_from_memory(bytes).ok()?;
+    img.apply_orientation(exif_orientation(bytes));
+    Some(img)
+}
+
+// Decode an image, apply its EXIF orientation and re-encode it as PNG
+fn apply_exif_orientation(bytes: &[u8]) -> Result<Vec<u8>, OcrError> {
+    let img = load_oriented_image(bytes).ok_or(OcrError::DecodeFailed)?;
+
+    let mut png = Vec::new();
+    img.write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+        .map_err(|_| OcrError::DecodeFailed)?;
+    Ok(png)
+}
+
+// Upscale factor capped so the enlarged image stays within MAX_UPSCALED_DIMENSION
+// (None when the image isn't enlarged)
+fn upscale_factor(width: u32, height: u32, upscale: f64) -> Option<f64> {
+    let factor = upscale.min(MAX_UPSCALED_DIMENSION as f64 / width.max(height) as f64);
+    if factor > 1.0 { Some(factor) } else { None }
+}
+
+// Enlarge (with Lanczos resampling) and/or preprocess an image as it's displayed,
+// and re-encode it as PNG
+fn prepare_image(bytes: &[u8], upscale: Option<f64>, preprocess: Option<Preprocess>) -> Result<Vec<u8>, OcrError> {
+    let mut img = load_oriented_image(bytes).ok_or(OcrError::DecodeFailed)?;
+    if let Some(factor) = upscale {
+        let width = (img.width() as f64 * factor).round() as u32;
+        let height = (img.height() as f64 * factor).round() as u32;
+        img = img.resize_exact(width, height, FilterType::Lanczos3);
+    }
+    if let Some(preprocess) = preprocess {
+        img = preprocess.apply(img);
+    }
+
+    let mut png = Vec::new();
+    img.write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+        .map_err(|_| OcrError::DecodeFailed)?;
+    Ok(png)
+}
+
+// Read the image size from the header without decoding the pixels
+fn image_dimensions(bytes: &[u8]) -> Option<(u32, u32)> {
+    let (width, height) = ImageReader::new(Cursor::new(bytes)).with_guessed_format().ok()?.into_dimensions().ok()?;
+    if width == 0 || height == 0 { None } else { Some((width, height)) }
+}
+
+// Rotate an encoded image clockwise and re-encode it as PNG
+fn rotate_image(bytes: &[u8], degrees: u32) -> Result<Vec<u8>, OcrError> {
+    // Rotate the image as it's displayed, and drop the EXIF orientation with the re-encoding
+    let img = load_oriented_image(bytes).ok_or(OcrError::DecodeFailed)?;
+    let img = match degrees {
+        90 => img.rotate90(),
+        180 => img.rotate180(),
+        270 => img.rotate270(),
+        _ => img,
+    };
+
+    let mut png = Vec::new();
+    img.write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+        .map_err(|_| OcrError::DecodeFailed)?;
+    Ok(png)
+}
+
+pub fn get_ocr_result_from_bytes(bytes: &[u8], options: &OCROptions) -> Result<OCRResult, OcrError> {
+    let format = detect_file_type(bytes).unwrap_or_default().to_string();
+    let dpi = image_io::dpi(bytes);
+
+    // Images the image crate can't decode (like HEIC/HEIF) are converted to PNG with ImageIO first.
+    // This keeps the image size used to scale the boxes (and the rotation and PDF output) working.
+    let converted;
+    let bytes = if image_io::is_heif(bytes) || image_dimensions(bytes).is_none() {
+        converted = image_io::decode_image(bytes).ok_or(OcrError::DecodeFailed)?;
+        &converted[..]
+    } else {
+        bytes
+    };
+
+    // Tiny images (thumbnails, broken files) only give Vision noise to recognize. The size of the
+    // input is checked, not the size of its rotated or flattened image.
+    if options.min_dimension > 0 && let Some((width, height)) = image_dimensions(bytes) && width.min(height) < options.min_dimension {
+        return Err(OcrError::ImageTooSmall(width, height));
+    }
+
+    // Boxes are computed on the rotated image, so they describe positions in the rotated image space
+    let rotated;
+    let bytes = if options.input_rotate != 0 {
+        rotated = rotate_image(bytes, options.input_rotate)?;
+        &rotated[..]
+    } else {
+        bytes
+    };
+
+    // The document is detected in the rotated image, and the flattened page is OCR'd instead of it
+    if options.detect_document && let Some((page, corners)) = flatten_document(bytes, options.retries)? {
+        let options = OCROptions { detect_document: false, input_rotate: 0, min_dimension: 0, ..options.clone() };
+        let mut ocr_result = get_ocr_result_from_bytes(&page, &options)?;
+        ocr_result.format = format;
+        ocr_result.dpi = dpi;
+        ocr_result.document = Some(corners);
+        return Ok(ocr_result);
+    }
+
+    // Vision returns coordinates in the image as displayed with its EXIF orientation, so the
+    // orientation is passed to the handler and applied to the image size used to scale the boxes
+    let orientation = exif_orientation(bytes);
+
+    // Without the image size every box would be scaled to zero area
+    let (mut width, mut height) = image_dimensions(bytes).ok_or(OcrError::DecodeFailed)?;
+    if matches!(orientation, Orientation::Rotate90 | Orientation::Rotate270 | Orientation::Rotate90FlipH | Orientation::Rotate270FlipH) {
+        (width, height) = (height, width);
+    }
+
+    // Small text is recognized better on an enlarged copy, and noisy backgrounds on a preprocessed
+    // one. Vision's coordinates are normalized, so the boxes are still scaled with the original size.
+    let upscale = upscale_factor(width, height, options.upscale);
+    let prepared;
+    let (bytes_to_ocr, orientation_to_ocr) = if upscale.is_some() || options.preprocess.is_some() {
+        prepared = prepare_image(bytes, upscale, options.preprocess)?;
+        (&prepared[..], Orientation::NoTransforms)
+    } else {
+        (bytes, orientation)
+    };
+    let request = reusable_text_request(options);
+
+    let request_super: Retained<VNRequest> = unsafe { Retained::cast_unchecked(request.clone()) };
+    let mut request_list = vec![request_super];
+    // The barcodes are detected in the same batch, on the same image
+    let barcode_request = options.barcodes.then(|| unsafe { VNDetectBarcodesRequest::new() });
+    if let Some(barcode_request) = &barcode_request {
+        request_list.push(unsafe { Retained::cast_unchecked(barcode_request.clone()) });
+    }
+    let requests = NSArray::from_retained_slice(&request_list);
+
+    let handler = image_request_handler(bytes_to_ocr, orientation_to_ocr);
+    perform_requests(&handler, &requests, options.retries)?;
+    
+    let mut items: Vec<OCRBoxItem> = Vec::new();
+    let mut result = String::new();
+    if let Some(observations) = request.results() {
+        for observation in observations {
+            let candidates = observation.topCandidates(options.candidates.max(1));
+            if let Some(candidate) = candidates.firstObject() {
+                let confidence = candidate.confidence();
+                if confidence < options.min_confidence {
+                    continue;
+                }
+
+                let text = format!("{}", candidate.string());
+                result.push_str(&format!("{}\n", candidate.string()));
+
+                let (rect_x, rect_y, rect_w, rect_h, rect) = pixel_rect(&observation, width, height);
+                let mut item = OCRBoxItem::new(text, rect_x, rect_y, rect_w, rect_h, rect, confidence);
+                item.alternatives = candidates.iter().skip(1).map(|c| c.string().to_string()).collect();
+                items.push(item);
+            }
+        }
+    }
+
+    let mut ocr_result = OCRResult::new(
+        result,
+        width,
+        height,
+        items
+    );
+    if let Some(observations) = barcode_request.and_then(|request| unsafe { request.results() }) {
+        ocr_result.barcodes = observations.iter()
+            .map(|observation| {
+                let (x, y, w, h, rect) = pixel_rect(&observation, width, height);
+                OCRBarcodeItem {
+                    symbology: unsafe { observation.symbology() }.to_string(),
+                    payload: unsafe { observation.payloadStringValue() }.map(|payload| payload.to_string()),
+                    x, y, w, h, rect,
+                    confidence: unsafe { observation.confidence() },
+                }
+            })
+            .collect();
+    }
+    ocr_result.format = format;
+    ocr_result.dpi = dpi;
+
+    if let Some(iou_threshold) = options.dedup_boxes {
+        ocr_result.dedup_boxes(iou_threshold);
+    }
+
+    if options.dedup_lines {
+        ocr_result.dedup_lines();
+    }
+
+    if let Some(gap) = options.paragraphs {
+        ocr_result.merge_paragraphs(gap);
+    }
+
+    if options.with_direction {
+        for item in &mut ocr_result.boxes {
+            item.direction = Some(TextDirection::detect(&item.text));
+        }
+    }
+
+    if options.with_quad {
+        for item in &mut ocr_result.boxes {
+            item.quad = Some(item.rect.quad());
+        }
+    }
+
+    if options.detect_line_language {
+        for item in &mut ocr_result.boxes {
+            item.language = detect_language(&item.text);
+        }
+    }
+
+    // Vision doesn't report the language it picked, so detect it in the recognized lines
+    if options.report_languages && !options.no_auto_language {
+        ocr_result.detected_languages = ocr_result.language_report();
+    }
+
+    if options.layout {
+        ocr_result.text = ocr_result.layout_text();
+    }
+
+    if options.dehyphenate {
+        ocr_result.text = dehyphenate(&ocr_result.text);
+    }
+
+    for (pattern, replacement) in &options.replacements {
+        ocr_result.text = pattern.replace_all(&ocr_result.text, replacement.as_str()).into_owned();
+    }
+
+    if let Some(pattern) = &options.grep {
+        ocr_result.text = ocr_result.text.lines()
+            .filter(|line| pattern.is_match(line))
+            .map(|line| format!("{}\n", line))
+            .collect();
+        if options.grep_boxes {
+            ocr_result.boxes.retain(|item| pattern.is_match(&item.text));
+        }
+    }
+
+    // Last, as the dedup and layout above work in pixels
+    if options.normalized {
+        ocr_result.normalize_boxes();
+    }
+
+    if options.keep_image {
+        ocr_result.image = Some(match orientation {
+            Orientation::NoTransforms => bytes.to_vec(),
+            _ => apply_exif_orientation(bytes)?,
+        });
+    }
+
+    Ok(ocr_result)
+}
+
+/// OCR a blank image, so that Vision loads its recognition model before the first real OCR,
+/// which would pay for it otherwise
+pub fn warm_up(options: &OCROptions) {
+    let mut png = Vec::new();
+    let blank = DynamicImage::new_luma8(WARM_UP_IMAGE_SIZE, WARM_UP_IMAGE_SIZE);
+    // The blank image is smaller than a large min_dimension, which would skip Vision
+    let options = OCROptions { min_dimension: 0, ..options.clone() };
+    if blank.write_to(&mut Cursor::new(&mut png), ImageFormat::Png).is_ok() {
+        let _ = get_ocr_result_from_bytes(&png, &options);
+    }
+}
+
+// Image request handler performing Vision requests on an encoded image shown with an orientation
+fn image_request_handler(bytes: &[u8], orientation: Orientation) -> Retained<VNImageRequestHandler> {
+    let data = NSData::with_bytes(bytes);
+    let handler_options = NSDictionary::<NSString, AnyObject>::new();
+    // initWithData:orientation:options: isn't generated by objc2-vision, as it needs ImageIO types
+    unsafe {
+        msg_send![
+            VNImageRequestHandler::alloc(),
+            initWithData: &*data,
+            orientation: cg_orientation(orientation),
+            options: &*handler_options
+        ]
+    }
+}
+
+// Perform Vision requests, retrying a failed attempt up to `retries` times with a growing backoff,
+// as Vision occasionally fails under load. The last error is returned when every attempt fails.
+fn perform_requests(handler: &VNImageRequestHandler, requests: &NSArray<VNRequest>, retries: u32) -> Result<(), OcrError> {
+    let mut backoff = RETRY_BACKOFF;
+    for _ in 0..retries {
+        if handler.performRequests_error(requests).is_ok() {
+            return Ok(());
+        }
+        thread::sleep(backoff);
+        backoff *= 2;
+    }
+    handler.performRequests_error(requests)
+        .map_err(|error| OcrError::VisionFailed(error.localizedDescription().to_string()))
+}
+
+// Flattened document encoded as PNG, and its corners in the image it was detected in
+type FlattenedDocument = (Vec<u8>, [[f64; 2]; 4]);
+
+// Detect the document in an image and warp its quad to a rectangle, the size of its longer
+// opposite edges. Returns the flattened document as PNG with its corners in the (oriented)
+// image, or None when there's no document in the image.
+fn flatten_document(bytes: &[u8], retries: u32) -> Result<Option<FlattenedDocument>, OcrError> {
+    let request = unsafe { VNDetectDocumentSegmentationRequest::new() };
+    let request_super: Retained<VNRequest> = unsafe { Retained::cast_unchecked(request.clone()) };
+    let handler = image_request_handler(bytes, exif_orientation(bytes));
+    perform_requests(&handler, &NSArray::from_retained_slice(&[request_super]), retries)?;
+    let Some(observation) = unsafe { request.results() }.and_then(|observations| observations.firstObject()) else {
+        return Ok(None);
+    };
+
+    let image = load_oriented_image(bytes).ok_or(OcrError::DecodeFailed)?.to_rgba8();
+    let (_, _, _, _, rect) = pixel_rect(&observation, image.width(), image.height());
+    let corners = rect.quad();
+    let distance = |a: [f64; 2], b: [f64; 2]| (a[0] - b[0]).hypot(a[1] - b[1]);
+    let width = distance(corners[0], corners[1]).max(distance(corners[3], corners[2])).round().max(1.0);
+    let height = distance(corners[0], corners[3]).max(distance(corners[1], corners[2])).round().max(1.0);
+
+    let from = corners.map(|[x, y]| (x as f32, y as f32));
+    let to = [(0.0, 0.0), (width as f32, 0.0), (width as f32, height as f32), (0.0, height as f32)];
+    let Some(projection) = Projection::from_control_points(from, to) else {
+        return Ok(None);
+    };
+    let mut page = RgbaImage::new(width as u32, height as u32);
+    warp_into(&image, &projection, Interpolation::Bilinear, Rgba([255, 255, 255, 255]), &mut page);
+
+    let mut png = Vec::new();
+    DynamicImage::ImageRgba8(page).write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+        .map_err(|_| OcrError::DecodeFailed)?;
+    Ok(Some((png, corners)))
+}
+
+// Pixel bounding box (x, y, w, h) and corners of an observation, whose normalized coordinates
+// have their origin at the bottom-left corner of the image
+fn pixel_rect(observation: &VNRectangleObservation, width: u32, height: u32) -> (f64, f64, f64, f64, OCRRectItem) {
+    let corners = unsafe {[
+        CGPoint{ x: observation.topLeft().x * width as f64, y: (1.0 - observation.topLeft().y) * height as f64 },
+        CGPoint{ x: observation.topRight().x * width as f64, y: (1.0 - observation.topRight().y) * height as f64 },
+        CGPoint{ x: observation.bottomRight().x * width as f64, y: (1.0 - observation.bottomRight().y) * height as f64 },
+        CGPoint{ x: observation.bottomLeft().x  * width as f64, y: (1.0 - observation.bottomLeft().y)  * height as f64 }
+    ]};
+
+    let min_x = corners.iter().map(|p| p.x).fold(f64::INFINITY, f64::min);
+    let max_x = corners.iter().map(|p| p.x).fold(f64::NEG_INFINITY, f64::max);
+    let min_y = corners.iter().map(|p| p.y).fold(f64::INFINITY, f64::min);
+    let max_y = corners.iter().map(|p| p.y).fold(f64::NEG_INFINITY, f64::max);
+
+    let rect = OCRRectItem::new(corners[0].x, corners[0].y, 
+                                corners[1].x, corners[1].y, 
+                                corners[2].x, corners[2].y, 
+                                corners[3].x, corners[3].y);
+
+    (min_x, min_y, max_x - min_x, max_y - min_y, rect)
+}
+
+/// Keep newline and tab, drop every other control character
+pub fn strip_control_chars(text: &str) -> String {
+    text.chars()
+        .filter(|c| !c.is_control() || *c == '\n' || *c == '\t')
+        .collect()
+}
+
+/// Join words hyphenated across line breaks: when a line ends with a lowercase letter and `-`
+/// and the next line starts with a lowercase letter, the hyphen is dropped and the next line's
+/// first word is moved up, e.g. "inter-\nnational team" becomes "international\nteam"
+pub fn dehyphenate(text: &str) -> String {
+    let mut lines: Vec<String> = text.lines().map(String::from).collect();
+    // Lines whose only word was moved up to the previous line
+    let mut emptied = vec![false; lines.len()];
+    for i in 0..lines.len().saturating_sub(1) {
+        let mut tail = lines[i].chars().rev();
+        if tail.next() != Some('-') || !tail.next().is_some_and(char::is_lowercase) {
+            continue;
+        }
+        let next = lines[i + 1].trim_start();
+        if !next.chars().next().is_some_and(char::is_lowercase) {
+            continue;
+        }
+
+        let (word, rest) = next.split_once(char::is_whitespace).unwrap_or((next, ""));
+        let (word, rest) = (word.to_string(), rest.trim_start().to_string());
+        lines[i].pop();
+        lines[i].push_str(&word);
+        emptied[i + 1] = rest.is_empty();
+        lines[i + 1] = rest;
+    }
+
+    lines.iter()
+        .zip(emptied)
+        .filter(|(_, emptied)| !emptied)
+        .map(|(line, _)| format!("{}\n", line))
+        .collect()
+}
+
+/// Detect the dominant language of a line with the NaturalLanguage framework
+pub fn detect_language(text: &str) -> Option<String> {
+    let language = unsafe { NLLanguageRecognizer::dominantLanguageForString(&NSString::from_str(text)) }?;
+    let language = language.to_string();
+    if language == "und" { None } else { Some(language) }
+}
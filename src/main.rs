@@ -1,50 +1,46 @@
-use clap::{Parser};
-use image::GenericImageView;
-use infer;
-use tower_http::{limit::RequestBodyLimitLayer, trace::{DefaultOnFailure, DefaultOnRequest, DefaultOnResponse, TraceLayer}};
-use tracing::Level;
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
-use std::{fs, io::{self, Write}, path::Path};
-use objc2::rc::Retained;
-use objc2::AnyThread;
-use objc2_vision::{
-    VNImageRequestHandler, VNRecognizeTextRequest, 
-    VNRecognizeTextRequestRevision3, VNRequest, 
-    VNRequestTextRecognitionLevel
+use clap::{builder::{PossibleValuesParser, TypedValueParser}, error::ErrorKind, CommandFactory, Parser, ValueEnum};
+use std::{collections::BTreeMap, fs, io::{self, IsTerminal, Read, Write}, net::IpAddr, path::{Path, PathBuf}, sync::{atomic::{AtomicUsize, Ordering}, mpsc, Arc}, thread, time::Duration};
+use macocr::{
+    get_ocr_results_from_bytes, is_ocr_input, recognition_revision, supported_languages, supported_revisions,
+    OCROptions, OCRResult, OcrError, Preprocess, RecognitionLevel
 };
-use objc2_foundation::{
-    NSArray, NSData, NSDictionary
-};
-use axum::{
-    extract::{DefaultBodyLimit, Multipart, Request}, 
-    http::{HeaderMap, StatusCode}, 
-    middleware::{self, Next}, 
-    response::{Html, IntoResponse, Response}, 
-    routing::{get, post}, 
-    Json, 
-    Router
-};
-use base64::{Engine as _, engine::general_purpose};
-use termcolor::{Color, ColorChoice, ColorSpec, StandardStream, WriteColor};
-use uuid::Uuid;
-use serde::Serialize;
+use indicatif::{ProgressBar, ProgressStyle};
 use regex::Regex;
-use core_graphics::geometry::CGPoint;
+use macocr::output::{file_results, render_coco, render_csv, render_json, render_kv, render_output, render_pdf, render_subtitles, render_table_csv, render_table_json, split_by_language, OCRFileResult, OutputFormat, SubtitleFormat};
+
+mod benchmark;
+mod cache;
+mod clipboard;
+mod metrics;
+mod server;
+mod watch;
 
-// app version
-const VERSION: &str = env!("CARGO_PKG_VERSION");
-// upload dir name
-const UPLOAD_DIR_NAME: &str = "macocr_uploads";
+use cache::Cache;
+use server::{run_server, LogFormat};
 
+// input file name that reads the image from stdin
+const STDIN_INPUT: &str = "-";
+// output file name that writes to stdout
+const STDOUT_OUTPUT: &str = "-";
+// input name of the pasteboard image in outputs and errors
+const CLIPBOARD_INPUT: &str = "clipboard";
+// largest --max-upload-size, so the limit in bytes fits in usize
+const MAX_UPLOAD_SIZE_MB: u64 = 4096;
+// URL schemes of inputs that are downloaded
+const URL_SCHEMES: [&str; 2] = ["http://", "https://"];
 
 /// OCR Tool using Vision Framework API
 #[derive(Parser, Debug)]
 #[command(author, version, about)]
 struct Args {
-    /// Input files
+    /// Input files, directories or http(s) URLs ("-" reads an image from stdin)
     #[arg(required(false))]
     files: Vec<String>,
 
+    /// Timeout in seconds for downloading URL inputs
+    #[arg(long, value_name = "SECS", default_value_t = 30, value_parser = clap::value_parser!(u64).range(1..))]
+    timeout: u64,
+
     /// OCR and export text files
     #[arg(short('o'), long, conflicts_with = "server")]
     ocr: bool,
@@ -57,494 +53,1090 @@ struct Args {
     #[arg(short('a'), long, default_value = "")]
     auth: String,
 
+    /// HTTP Bearer token auth (accepted alongside --auth when both are given)
+    #[arg(long, value_name = "SECRET", requires = "server", value_parser = clap::builder::NonEmptyStringValueParser::new())]
+    token: Option<String>,
+
     /// HTTP port number
     #[arg(short('p'), long, default_value_t = 8000)]
-    port: u32,
+    port: u16,
+
+    /// Serve HTTP on this Unix domain socket instead of a TCP port
+    #[arg(long, value_name = "PATH", requires = "server", conflicts_with_all = ["port", "host", "rate_limit"])]
+    unix_socket: Option<String>,
+
+    /// IP address the HTTP server binds to (use 127.0.0.1 to only accept local connections)
+    #[arg(long, default_value = "0.0.0.0")]
+    host: IpAddr,
+
+    /// Strip control characters from recognized text (always on when stdout is a TTY)
+    #[arg(long)]
+    strip_control_chars: bool,
+
+    /// Only OCR the given frame (0-based) of an animated GIF/WebP, or page of a PDF/TIFF
+    #[arg(long)]
+    frame: Option<usize>,
+
+    /// Output format
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    format: OutputFormat,
+
+    /// Parse "Label: value" lines into a JSON object (same as --format kv)
+    #[arg(long, conflicts_with = "format")]
+    kv: bool,
+
+    /// Detect a table in the boxes and output its grid as JSON, or as CSV with --format csv
+    #[arg(long, conflicts_with = "kv")]
+    tables: bool,
+
+    /// Rotate input images clockwise by the given degrees before OCR; boxes refer to the rotated image
+    #[arg(long, default_value_t = 0, value_parser = PossibleValuesParser::new(["0", "90", "180", "270"]).map(|s| s.parse::<u32>().unwrap()))]
+    input_rotate: u32,
+
+    /// Number of worker threads for the HTTP server (default: number of CPU cores)
+    #[arg(long, value_parser = clap::value_parser!(u16).range(1..), requires = "server")]
+    server_threads: Option<u16>,
+
+    /// Format of the server logs
+    #[arg(long, value_enum, value_name = "FORMAT", default_value_t = LogFormat::Text, requires = "server")]
+    log_format: LogFormat,
+
+    /// Maximum number of OCR calls the server runs at once; further uploads wait for their turn
+    #[arg(long, value_name = "N", requires = "server", value_parser = clap::value_parser!(u16).range(1..))]
+    max_concurrent_ocr: Option<u16>,
+
+    /// Enlarge images by this factor (1.0-8.0) before OCR to help with tiny text; boxes stay in original pixels
+    #[arg(long, value_name = "FACTOR", default_value_t = 1.0, value_parser = parse_upscale_factor)]
+    upscale: f64,
+
+    /// Preprocess images before OCR, e.g. for photographed documents with noisy backgrounds
+    #[arg(long, value_enum)]
+    preprocess: Option<Preprocess>,
+
+    /// Keep the approximate spatial layout (columns and indentation) of the text with spaces
+    #[arg(long)]
+    layout: bool,
+
+    /// Join words hyphenated across line breaks (lossy: also joins real hyphenated compounds)
+    #[arg(long)]
+    dehyphenate: bool,
+
+    /// Drop lines repeating the line before them (with their boxes)
+    #[arg(long)]
+    dedup: bool,
+
+    /// Merge the lines into paragraph blocks with their joined text and a box around them
+    #[arg(long)]
+    paragraphs: bool,
+
+    /// With --paragraphs, the largest vertical gap between the lines of a paragraph, in line heights
+    #[arg(long, value_name = "LINES", default_value_t = 0.5, requires = "paragraphs", value_parser = parse_gap)]
+    paragraph_gap: f64,
+
+    /// Replace regex matches in the recognized text (repeatable, applied in order; $1 refers to a group)
+    #[arg(long, value_name = "PATTERN=>REPLACEMENT", value_parser = parse_replacement)]
+    replace: Vec<(Regex, String)>,
+
+    /// Keep only the recognized lines matching this regex
+    #[arg(long, value_name = "PATTERN", value_parser = parse_regex)]
+    grep: Option<Regex>,
+
+    /// With --grep, keep only the boxes matching it as well (e.g. for --format json)
+    #[arg(long, requires = "grep")]
+    grep_box: bool,
+
+    /// Drop boxes whose IoU with a higher-confidence box exceeds this threshold (0.0-1.0)
+    #[arg(long, value_name = "IOU", value_parser = parse_unit_interval)]
+    dedup_boxes: Option<f64>,
+
+    /// Detect barcodes and QR codes too, and add their symbology, payload and box as "barcodes" (JSON outputs)
+    #[arg(long)]
+    barcodes: bool,
+
+    /// Detect the document in photos, flatten it and OCR it without the background (adds its corners as "document")
+    #[arg(long)]
+    detect_document: bool,
+
+    /// Add the base text direction (ltr/rtl) of each box
+    #[arg(long)]
+    with_direction: bool,
+
+    /// Add the four corner points of each box as a quad of [x, y] pairs
+    #[arg(long)]
+    with_quad: bool,
+
+    /// Output box coordinates as fractions (0.0-1.0) of the image size, with the origin at the top-left
+    #[arg(long)]
+    normalized: bool,
+
+    /// With --ocr, write one {stem}.{lang}.txt per detected line language
+    #[arg(long, requires = "ocr")]
+    split_by_language: bool,
+
+    /// Show a thumbnail (max width/height in px) of the uploaded image on the HTML result page
+    #[arg(long, value_name = "PX", requires = "server")]
+    preview_size: Option<u32>,
+
+    /// Maximum size of an upload request in MB (up to 4096)
+    #[arg(long, value_name = "MB", default_value_t = 100, value_parser = clap::value_parser!(u64).range(1..=MAX_UPLOAD_SIZE_MB))]
+    max_upload_size: u64,
+
+    /// Save a copy of the uploaded files in the upload dir
+    #[arg(long, requires = "server")]
+    keep_uploads: bool,
+
+    /// Directory the uploads are kept in, e.g. on a larger volume (implies --keep-uploads)
+    #[arg(long, value_name = "PATH", requires = "server")]
+    upload_dir: Option<PathBuf>,
+
+    /// Expose Prometheus metrics (uploads, OCR outcomes and latency) on GET /metrics
+    #[arg(long, requires = "server")]
+    metrics: bool,
+
+    /// Limit each client IP to this many requests per minute to the upload endpoints
+    #[arg(long, value_name = "N", requires = "server", value_parser = clap::value_parser!(u32).range(1..))]
+    rate_limit: Option<u32>,
+
+    /// Allow cross-origin requests from these origins (comma-separated, or "*" for any origin)
+    #[arg(long, value_name = "ORIGINS", requires = "server", value_parser = parse_cors_origins)]
+    cors_origin: Option<String>,
+
+    /// Draw the detected boxes on the HTML preview thumbnail
+    #[arg(long, requires = "preview_size")]
+    preview_boxes: bool,
+
+    /// Stop at the first input that can't be OCR'd instead of continuing with the next ones
+    #[arg(long)]
+    strict: bool,
+
+    /// Don't print the "input --> exported file" lines and the progress bar of --ocr
+    #[arg(short('q'), long, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Report on stderr which inputs were OCR'd successfully, besides the failures
+    #[arg(short('v'), long)]
+    verbose: bool,
+
+    /// OCR the image on the clipboard instead of input files
+    #[arg(long, conflicts_with_all = ["files", "glob", "watch", "ocr", "server"])]
+    clipboard: bool,
+
+    /// Copy the output to the clipboard instead of printing it
+    #[arg(long, conflicts_with_all = ["ocr", "server", "watch", "append_to"])]
+    copy: bool,
+
+    /// Append the output to a file (locked while writing) instead of printing it to stdout
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["ocr", "server"])]
+    append_to: Option<String>,
+
+    /// Recognition languages in priority order, e.g. zh-Hans,en-US
+    #[arg(long, value_delimiter = ',')]
+    languages: Vec<String>,
+
+    /// Turn off automatic language detection, so only the --languages are recognized
+    #[arg(long, requires = "languages")]
+    no_auto_language: bool,
+
+    /// Text recognition level
+    #[arg(long, value_enum, default_value_t = RecognitionLevel::Accurate)]
+    level: RecognitionLevel,
+
+    /// Drop lines whose recognition confidence is below this threshold (0.0-1.0)
+    #[arg(long, default_value_t = 0.0, value_parser = parse_unit_interval)]
+    min_confidence: f64,
+
+    /// Number of candidate strings per line (1-10); extra ones are returned as alternatives
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u8).range(1..=10))]
+    candidates: u8,
+
+    /// Ignore text smaller than this height, relative to the image height (0.0-1.0)
+    #[arg(long, default_value_t = 0.0, value_parser = parse_unit_interval)]
+    min_text_height: f64,
+
+    /// Skip images smaller than this many pixels on a side with a warning, e.g. thumbnails or broken files
+    #[arg(long, value_name = "PX", default_value_t = 0)]
+    min_dimension: u32,
+
+    /// Words to bias recognition toward, e.g. product names or codes
+    #[arg(long, value_delimiter = ',')]
+    custom_words: Vec<String>,
+
+    /// File with additional custom words, one per line
+    #[arg(long, value_name = "PATH")]
+    custom_words_file: Option<String>,
+
+    /// Cache the OCR results in this directory, keyed by the input contents and the OCR settings
+    #[arg(long, value_name = "DIR")]
+    cache: Option<PathBuf>,
+
+    /// Add the files matching a glob pattern, e.g. "scans/**/*.png" (can be repeated)
+    #[arg(long, value_name = "PATTERN")]
+    glob: Vec<String>,
+
+    /// With --ocr, write the texts of all files to one output with a "==== file ====" header per file
+    #[arg(long, requires = "ocr", conflicts_with_all = ["output_dir", "split_by_language"])]
+    combine: bool,
+
+    /// Output of --combine ("-" for stdout, the default)
+    #[arg(long, value_name = "FILE", requires = "combine")]
+    output: Option<String>,
+
+    /// With --ocr, write the exported files to this directory (created if needed)
+    #[arg(long, value_name = "PATH", requires = "ocr")]
+    output_dir: Option<String>,
+
+    /// With --ocr, also append the full OCR result of every input as a JSON line to this file
+    #[arg(long, value_name = "FILE", requires = "ocr")]
+    ndjson: Option<String>,
+
+    /// With --ocr, don't overwrite existing files
+    #[arg(short('n'), long, requires = "ocr", conflicts_with = "force")]
+    no_clobber: bool,
+
+    /// With --ocr, overwrite existing files without a notice
+    #[arg(short('f'), long, requires = "ocr")]
+    force: bool,
+
+    /// List the inputs, whether they can be OCR'd and where their output would go, without OCR'ing them
+    #[arg(long, conflicts_with_all = ["server", "watch", "clipboard", "benchmark", "subtitles"])]
+    dry_run: bool,
+
+    /// OCR the input files RUNS times each and report the latencies on stderr instead of the text
+    #[arg(long, value_name = "RUNS", value_parser = clap::value_parser!(u16).range(1..), conflicts_with_all = ["server", "ocr", "watch", "clipboard", "cache", "copy"])]
+    benchmark: Option<u16>,
+
+    /// OCR the input files as the ordered frames of a video and output their text as subtitles
+    #[arg(long, value_enum, value_name = "FORMAT", requires = "frame_timing", conflicts_with_all = ["server", "ocr", "watch", "clipboard", "benchmark"])]
+    subtitles: Option<SubtitleFormat>,
+
+    /// With --subtitles, the frame rate of the input frames (frames per second)
+    #[arg(long, value_name = "RATE", group = "frame_timing", requires = "subtitles", value_parser = parse_frame_rate)]
+    fps: Option<f64>,
+
+    /// With --subtitles, the time (in seconds) of every input frame, comma-separated
+    #[arg(long, value_name = "SECONDS", group = "frame_timing", requires = "subtitles", value_delimiter = ',')]
+    timestamps: Vec<f64>,
+
+    /// Number of files to OCR in parallel
+    #[arg(short('j'), long, default_value_t = 1, value_parser = clap::value_parser!(u16).range(1..))]
+    jobs: u16,
+
+    /// Watch a directory and OCR new images as they appear (with --ocr, export next to each image)
+    #[arg(long, value_name = "DIR", conflicts_with_all = ["server", "files", "glob"])]
+    watch: Option<String>,
+
+    /// Descend into subdirectories of input (or watched) directories
+    #[arg(short('r'), long)]
+    recursive: bool,
+
+    /// Vision text recognition request revision (default: 3); falls back to the highest supported one
+    #[arg(long, value_name = "N")]
+    revision: Option<usize>,
+
+    /// Retry a failed Vision request up to N times, with a short backoff between attempts
+    #[arg(long, value_name = "N", default_value_t = 1)]
+    retries: u32,
+
+    /// List the supported recognition languages and exit
+    #[arg(long, exclusive = true)]
+    list_languages: bool,
 }
 
-// Upload Json Response
-#[derive(Serialize)]
-struct UploadResponse {
-    success: bool,
-    message: String,
-    ocr_result: String,
-    image_width: u32,
-    image_height: u32,
-    ocr_boxes: Vec<OCRBoxItem>
+fn main() {
+    let mut args = Args::parse();
+    if args.kv {
+        args.format = OutputFormat::Kv;
+    }
+    if args.tables {
+        args.format = match args.format {
+            OutputFormat::Text | OutputFormat::Json => OutputFormat::Table,
+            OutputFormat::Csv => OutputFormat::TableCsv,
+            _ => Args::command()
+                .error(ErrorKind::ArgumentConflict, "--tables can only be used with the json and csv formats")
+                .exit(),
+        };
+    }
+    let strip_stdout = args.strip_control_chars || (args.append_to.is_none() && io::stdout().is_terminal());
+    let mut custom_words = args.custom_words.clone();
+    if let Some(path) = &args.custom_words_file {
+        match fs::read_to_string(path) {
+            Ok(contents) => custom_words.extend(
+                contents.lines().map(str::trim).filter(|word| !word.is_empty()).map(String::from)
+            ),
+            Err(e) => {
+                eprintln!("Failed to read {}: {}", path, e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let options = OCROptions {
+        input_rotate: args.input_rotate,
+        dedup_boxes: args.dedup_boxes,
+        dedup_lines: args.dedup,
+        paragraphs: args.paragraphs.then_some(args.paragraph_gap),
+        min_confidence: args.min_confidence as f32,
+        candidates: args.candidates.into(),
+        min_text_height: args.min_text_height as f32,
+        custom_words,
+        with_direction: args.with_direction,
+        with_quad: args.with_quad,
+        normalized: args.normalized,
+        upscale: args.upscale,
+        preprocess: args.preprocess,
+        detect_line_language: args.split_by_language,
+        keep_image: args.format == OutputFormat::Pdf,
+        layout: args.layout,
+        dehyphenate: args.dehyphenate,
+        replacements: args.replace.clone(),
+        grep: args.grep.clone(),
+        grep_boxes: args.grep_box,
+        languages: args.languages.clone(),
+        no_auto_language: args.no_auto_language,
+        // Only the JSON outputs include the detected languages
+        report_languages: matches!(args.format, OutputFormat::Json | OutputFormat::Jsonl) || args.ndjson.is_some(),
+        level: args.level,
+        revision: args.revision.map(supported_revision),
+        barcodes: args.barcodes,
+        detect_document: args.detect_document,
+        retries: args.retries,
+        min_dimension: args.min_dimension,
+    };
+
+    // hOCR, ALTO, PDF and COCO are laid out in pixels
+    if args.normalized && matches!(args.format, OutputFormat::Hocr | OutputFormat::Alto | OutputFormat::Pdf | OutputFormat::Coco) {
+        Args::command()
+            .error(ErrorKind::ArgumentConflict, "--normalized can't be used with the hocr, alto, pdf and coco formats")
+            .exit();
+    }
+
+    if args.copy && args.format == OutputFormat::Pdf {
+        Args::command()
+            .error(ErrorKind::ArgumentConflict, "--copy can't be used with the pdf format")
+            .exit();
+    }
+
+    // A PDF can't be appended to another one
+    if args.append_to.is_some() && args.format == OutputFormat::Pdf {
+        Args::command()
+            .error(ErrorKind::ArgumentConflict, "--append-to can't be used with the pdf format")
+            .exit();
+    }
+
+    // The watched images are printed one after another, which doesn't make a valid document
+    if args.watch.is_some() && !args.ocr && matches!(args.format, OutputFormat::Hocr | OutputFormat::Alto | OutputFormat::Pdf) {
+        Args::command()
+            .error(ErrorKind::MissingRequiredArgument, "--watch needs -o with the hocr, alto and pdf formats")
+            .exit();
+    }
+
+    if args.list_languages {
+        list_languages(&options);
+        return;
+    }
+
+    if !args.dry_run {
+        warn_unsupported_languages(&options);
+    }
+
+    // The images kept for the PDF output aren't cached
+    let cache = match args.cache.as_deref().filter(|_| !options.keep_image && !args.dry_run) {
+        Some(dir) => match Cache::new(dir, &options, args.frame) {
+            Ok(cache) => Some(cache),
+            Err(e) => {
+                eprintln!("Failed to create {}: {}", dir.display(), e);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+
+    if let Some(dir) = &args.watch {
+        if let Err(e) = watch::run_watch(dir, &args, &options, cache.as_ref(), strip_stdout) {
+            eprintln!("Failed to watch {}: {}", dir, e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if args.clipboard {
+        let Some(bytes) = clipboard::read_image() else {
+            eprintln!("The clipboard doesn't contain an image");
+            std::process::exit(1);
+        };
+        match ocr_bytes(bytes, args.frame, &options, cache.as_ref()) {
+            Ok(mut ocr_results) => {
+                report_success(&args, CLIPBOARD_INPUT, &ocr_results);
+                if strip_stdout {
+                    ocr_results.iter_mut().for_each(OCRResult::strip_control_chars);
+                }
+                let output = render_output(CLIPBOARD_INPUT, &ocr_results, args.format);
+                if args.copy {
+                    copy_output(&output);
+                } else {
+                    write_output(&output, args.append_to.as_deref());
+                }
+            }
+            Err(e) => {
+                eprintln!("{}: {}", CLIPBOARD_INPUT, e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    // Read the image from stdin when it's piped in without any input files
+    let mut paths = args.files.clone();
+    for pattern in &args.glob {
+        match glob::glob(pattern) {
+            Ok(entries) => paths.extend(
+                entries.filter_map(Result::ok).filter_map(|path| path.to_str().map(String::from))
+            ),
+            Err(e) => {
+                eprintln!("Invalid glob pattern {}: {}", pattern, e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let mut files = expand_inputs(&paths, args.recursive);
+    if paths.is_empty() && args.glob.is_empty() && !args.server && !io::stdin().is_terminal() {
+        files.push(InputFile::new(STDIN_INPUT.to_string(), None));
+    }
+
+    if files.is_empty() && !args.server {
+        if paths.is_empty() && args.glob.is_empty() && !args.ocr {
+            // Nothing to do at all, e.g. on a first run without arguments
+            eprint!("{}", Args::command().render_help());
+            std::process::exit(2);
+        }
+        eprintln!("Warning: no input files were given");
+    }
+
+    // hOCR and ALTO documents are titled with their input, so several inputs need one file each
+    if files.len() > 1 && !args.ocr && matches!(args.format, OutputFormat::Hocr | OutputFormat::Alto) {
+        Args::command()
+            .error(ErrorKind::MissingRequiredArgument, "several inputs need -o with the hocr and alto formats (one file per input)")
+            .exit();
+    }
+
+    // Whether any input couldn't be OCR'd, for the exit code
+    let mut failed = false;
+
+    if args.dry_run {
+        failed = dry_run(&args, &files);
+    } else if let Some(runs) = args.benchmark {
+        failed = benchmark::run_benchmark(&files, runs, &args, &options);
+    } else if let Some(format) = args.subtitles {
+        if !args.timestamps.is_empty() && args.timestamps.len() != files.len() {
+            Args::command()
+                .error(ErrorKind::WrongNumberOfValues, format!("--timestamps has {} times for {} frames", args.timestamps.len(), files.len()))
+                .exit();
+        }
+
+        let mut texts = Vec::new();
+        for (input, ocr_results) in files.iter().zip(get_all_ocr_results(&files, args.frame, &options, cache.as_ref(), args.jobs.into(), Duration::from_secs(args.timeout))) {
+            // A frame that can't be OCR'd ends the previous cue like a frame without text
+            let text = match ocr_results {
+                Ok(ocr_results) => ocr_results.iter().map(|r| r.text.as_str()).collect(),
+                Err(e) => {
+                    failed |= report_failure(&args, &input.path, &e);
+                    String::new()
+                }
+            };
+            texts.push(text);
+        }
+
+        let (frames, end): (Vec<(f64, &str)>, f64) = match args.fps {
+            Some(fps) => {
+                let frames = texts.iter().enumerate().map(|(index, text)| (index as f64 / fps, text.as_str())).collect();
+                (frames, texts.len() as f64 / fps)
+            }
+            None => {
+                // The last frame is shown as long as the one before it, or for a second
+                let times = &args.timestamps;
+                let last_duration = match times[..] {
+                    [.., before, last] => last - before,
+                    _ => 1.0,
+                };
+                let end = times.last().map_or(0.0, |last| last + last_duration);
+                (times.iter().copied().zip(texts.iter().map(String::as_str)).collect(), end)
+            }
+        };
+        let output = render_subtitles(&frames, end, format);
+        if args.copy {
+            copy_output(output.as_bytes());
+        } else {
+            write_output(output.as_bytes(), args.append_to.as_deref());
+        }
+    } else if !args.ocr && !args.server {
+        // JSON, CSV, COCO, key-value and PDF outputs of all files are printed together as one array / table / dataset / object / document
+        let combine_outputs = matches!(args.format, OutputFormat::Json | OutputFormat::Csv | OutputFormat::Coco | OutputFormat::Kv | OutputFormat::Table | OutputFormat::TableCsv | OutputFormat::Pdf);
+        let mut combined_inputs: Vec<(&str, Vec<OCRResult>)> = Vec::new();
+        // With --copy, the outputs are collected and copied to the clipboard at the end
+        let mut copied = args.copy.then(Vec::new);
+
+        for (input, ocr_results) in files.iter().zip(get_all_ocr_results(&files, args.frame, &options, cache.as_ref(), args.jobs.into(), Duration::from_secs(args.timeout))) {
+            let file = input.path.as_str();
+            let mut ocr_results = match ocr_results {
+                Ok(ocr_results) => ocr_results,
+                Err(e) => {
+                    failed |= report_failure(&args, file, &e);
+                    continue;
+                }
+            };
+            report_success(&args, file, &ocr_results);
+            if strip_stdout {
+                ocr_results.iter_mut().for_each(OCRResult::strip_control_chars);
+            }
+            if combine_outputs {
+                combined_inputs.push((file, ocr_results));
+            } else if let Some(copied) = &mut copied {
+                copied.extend(render_output(file, &ocr_results, args.format));
+            } else {
+                write_output(&render_output(file, &ocr_results, args.format), args.append_to.as_deref());
+            }
+        }
+
+        if args.format == OutputFormat::Pdf {
+            // Every page of every input goes into one document, as PDFs can't be concatenated
+            if !combined_inputs.is_empty() {
+                let pages: Vec<OCRResult> = combined_inputs.into_iter().flat_map(|(_, ocr_results)| ocr_results).collect();
+                write_output(&render_pdf(&pages), None);
+            }
+        } else if !combined_inputs.is_empty() || (args.format == OutputFormat::Json && files.len() > 1) {
+            // The JSON of several files is always an array, even when some or all of them failed
+            let inputs = combined_inputs.iter().map(|(file, ocr_results)| (*file, ocr_results.as_slice()));
+            let output = if args.format == OutputFormat::Csv {
+                render_csv(inputs, files.len() > 1)
+            } else if args.format == OutputFormat::Coco {
+                render_coco(inputs)
+            } else if args.format == OutputFormat::Kv {
+                render_kv(inputs, files.len() > 1)
+            } else if args.format == OutputFormat::Table {
+                render_table_json(inputs, files.len() > 1)
+            } else if args.format == OutputFormat::TableCsv {
+                render_table_csv(inputs, files.len() > 1)
+            } else {
+                let entries: Vec<OCRFileResult> = combined_inputs.iter()
+                    .flat_map(|(file, ocr_results)| file_results(file, ocr_results))
+                    .collect();
+                render_json(&entries, files.len() > 1)
+            };
+            if let Some(copied) = &mut copied {
+                copied.extend(output.into_bytes());
+            } else {
+                write_output(output.as_bytes(), args.append_to.as_deref());
+            }
+        }
+
+        if let Some(copied) = copied {
+            copy_output(&copied);
+        }
+    } else if args.ocr {
+        // With --combine, the texts of all files are written to one output instead of exported per file
+        let combine_to_stdout = args.output.as_deref().is_none_or(|output| output == STDOUT_OUTPUT);
+        let mut combined = if args.combine {
+            match open_combined_output(args.output.as_deref()) {
+                Ok(writer) => Some(writer),
+                Err(e) => {
+                    eprintln!("Failed to create {}: {}", args.output.as_deref().unwrap_or_default(), e);
+                    std::process::exit(1);
+                }
+            }
+        } else {
+            None
+        };
+        let strip_exports = args.strip_control_chars || (args.combine && combine_to_stdout && io::stdout().is_terminal());
+
+        let progress = new_progress_bar(&args, files.len());
+        let mut all_ocr_results = get_all_ocr_results(&files, args.frame, &options, cache.as_ref(), args.jobs.into(), Duration::from_secs(args.timeout));
+        for input in &files {
+            let file = input.path.as_str();
+            progress.set_message(file.to_string());
+            let Some(ocr_results) = all_ocr_results.next() else {
+                break;
+            };
+            // Hide the progress bar while printing, so it doesn't mix with the reports
+            progress.suspend(|| {
+                let mut ocr_results = match ocr_results {
+                    Ok(ocr_results) => ocr_results,
+                    Err(e) => {
+                        failed |= report_failure(&args, file, &e);
+                        return;
+                    }
+                };
+                report_success(&args, file, &ocr_results);
+                if strip_exports {
+                    ocr_results.iter_mut().for_each(OCRResult::strip_control_chars);
+                }
+                if let Some(ndjson) = &args.ndjson {
+                    write_output(&render_output(file, &ocr_results, OutputFormat::Jsonl), Some(ndjson));
+                }
+                if let Some(writer) = &mut combined {
+                    let text: String = ocr_results.iter().map(|r| r.text.as_str()).collect();
+                    if let Err(e) = write!(writer, "==== {} ====\n{}", file, text) {
+                        eprintln!("Failed to write the combined output: {}", e);
+                        std::process::exit(1);
+                    }
+                } else if let Some(stem) = input.export_stem(args.output_dir.as_deref().map(Path::new)) {
+                    export_results(&args, file, &stem, &ocr_results);
+                }
+            });
+            progress.inc(1);
+        }
+        progress.finish_and_clear();
+
+        if let Some(mut writer) = combined {
+            let _ = writer.flush();
+        }
+    }
+
+    if failed {
+        std::process::exit(1);
+    }
+
+    if args.server {
+        let mut runtime = tokio::runtime::Builder::new_multi_thread();
+        if let Some(threads) = args.server_threads {
+            runtime.worker_threads(threads.into());
+        }
+        runtime.enable_all().build().unwrap().block_on(run_server(&args, options));
+    }
 }
 
-#[derive(Serialize)]
-struct OCRBoxItem {
-    text: String,
-    x: f64,
-    y: f64,
-    w: f64,
-    h: f64,
-    rect: OCRRectItem
+// Parse a value in the 0.0-1.0 range
+fn parse_unit_interval(s: &str) -> Result<f64, String> {
+    let value: f64 = s.parse().map_err(|_| format!("`{}` isn't a number", s))?;
+    if (0.0..=1.0).contains(&value) {
+        Ok(value)
+    } else {
+        Err(format!("`{}` is not in 0.0-1.0", s))
+    }
 }
 
-impl OCRBoxItem {
-    fn new(text: String, x: f64, y: f64, w: f64, h: f64, rect: OCRRectItem) -> Self {
-        OCRBoxItem { text, x, y, w, h, rect }
+// Parse an upscale factor in the 1.0-8.0 range
+fn parse_upscale_factor(s: &str) -> Result<f64, String> {
+    let value: f64 = s.parse().map_err(|_| format!("`{}` isn't a number", s))?;
+    if (1.0..=8.0).contains(&value) {
+        Ok(value)
+    } else {
+        Err(format!("`{}` is not in 1.0-8.0", s))
     }
 }
 
-#[derive(Serialize)]
-struct OCRRectItem {
-    top_left_x: f64,
-    top_left_y: f64,
-    top_right_x: f64,
-    top_right_y: f64,
-    bottom_right_x: f64,
-    bottom_right_y: f64,
-    bottom_left_x: f64,
-    bottom_left_y: f64,
+// Parse a non-negative gap
+fn parse_gap(s: &str) -> Result<f64, String> {
+    let value: f64 = s.parse().map_err(|_| format!("`{}` isn't a number", s))?;
+    if value >= 0.0 {
+        Ok(value)
+    } else {
+        Err(format!("`{}` is negative", s))
+    }
 }
 
-impl OCRRectItem {
-    fn new(top_left_x: f64, top_left_y: f64, top_right_x: f64, top_right_y: f64, 
-           bottom_right_x: f64, bottom_right_y: f64, bottom_left_x: f64, bottom_left_y: f64) -> Self {
-        OCRRectItem { top_left_x, top_left_y, top_right_x, top_right_y, 
-                      bottom_right_x, bottom_right_y, bottom_left_x, bottom_left_y }
+// Parse a positive frame rate
+fn parse_frame_rate(s: &str) -> Result<f64, String> {
+    let value: f64 = s.parse().map_err(|_| format!("`{}` isn't a number", s))?;
+    if value > 0.0 && value.is_finite() {
+        Ok(value)
+    } else {
+        Err(format!("`{}` isn't a positive frame rate", s))
     }
 }
 
-#[derive(Serialize)]
-struct OCRResult {
-    text: String,
-    image_width: u32,
-    image_height: u32,
-    boxes: Vec<OCRBoxItem>
+// Parse a regex
+fn parse_regex(s: &str) -> Result<Regex, String> {
+    Regex::new(s).map_err(|e| e.to_string())
 }
 
-impl OCRResult {
-    fn new(text: String, image_width: u32, image_height: u32, boxes: Vec<OCRBoxItem>) -> Self {
-        OCRResult {
-            text,
-            image_width,
-            image_height,
-            boxes,
+// Parse a PATTERN=>REPLACEMENT regex substitution
+fn parse_replacement(s: &str) -> Result<(Regex, String), String> {
+    let (pattern, replacement) = s.split_once("=>").ok_or_else(|| format!("`{}` isn't in the PATTERN=>REPLACEMENT form", s))?;
+    let pattern = parse_regex(pattern)?;
+    Ok((pattern, replacement.to_string()))
+}
+
+// Parse a comma-separated CORS origin allowlist, or "*" on its own
+fn parse_cors_origins(s: &str) -> Result<String, String> {
+    if s == "*" {
+        return Ok(s.to_string());
+    }
+    for origin in s.split(',').map(str::trim) {
+        if !origin.bytes().all(|b| b.is_ascii_graphic()) || origin == "*" || !origin.contains("://") || origin.ends_with('/') {
+            return Err(format!("`{}` isn't an origin like https://example.com", origin));
         }
     }
+    Ok(s.to_string())
 }
 
-#[tokio::main]
-async fn main() {
-    let args = Args::parse();
+// Read an input file, stdin for "-", or download an http(s) URL
+fn read_input(path: &str, timeout: Duration) -> io::Result<Vec<u8>> {
+    if path == STDIN_INPUT {
+        let mut bytes = Vec::new();
+        io::stdin().read_to_end(&mut bytes)?;
+        Ok(bytes)
+    } else if is_url(path) {
+        download(path, timeout).map_err(io::Error::other)
+    } else {
+        fs::read(path)
+    }
+}
 
-    if !args.ocr && !args.server {
-        for file in args.files {
-            if is_image(&file) {
-                if let Ok(ocr_result) = get_ocr_result(&file) {
-                    print!("{}", ocr_result.text);
+fn is_url(path: &str) -> bool {
+    URL_SCHEMES.iter().any(|scheme| path.starts_with(scheme))
+}
+
+// Download a URL, following redirects
+fn download(url: &str, timeout: Duration) -> reqwest::Result<Vec<u8>> {
+    let client = reqwest::blocking::Client::builder().timeout(timeout).build()?;
+    let response = client.get(url).send()?.error_for_status()?;
+    Ok(response.bytes()?.to_vec())
+}
+
+// OCR an input file (or stdin or URL), one result per animation frame
+fn get_ocr_results(path: &str, frame: Option<usize>, options: &OCROptions, cache: Option<&Cache>, timeout: Duration) -> Result<Vec<OCRResult>, OcrError> {
+    ocr_bytes(read_input(path, timeout)?, frame, options, cache)
+}
+
+// OCR the bytes of an input, loading the results from the cache and storing them in it when given
+fn ocr_bytes(bytes: Vec<u8>, frame: Option<usize>, options: &OCROptions, cache: Option<&Cache>) -> Result<Vec<OCRResult>, OcrError> {
+    let Some(cache) = cache else {
+        return get_ocr_results_from_bytes(bytes, frame, options);
+    };
+    if let Some(ocr_results) = cache.get(&bytes) {
+        return Ok(ocr_results);
+    }
+    let ocr_results = get_ocr_results_from_bytes(bytes.clone(), frame, options)?;
+    if let Err(e) = cache.put(&bytes, &ocr_results) {
+        eprintln!("Failed to cache the OCR results: {}", e);
+    }
+    Ok(ocr_results)
+}
+
+// OCR the input files on up to `jobs` threads, yielding the results in input order as soon as
+// they're ready. Vision requests are thread-safe as long as each one is performed by its own
+// VNImageRequestHandler, which get_ocr_result_from_bytes creates for every image.
+fn get_all_ocr_results(files: &[InputFile], frame: Option<usize>, options: &OCROptions, cache: Option<&Cache>, jobs: usize, timeout: Duration) -> OrderedResults {
+    let paths: Arc<Vec<String>> = Arc::new(files.iter().map(|input| input.path.clone()).collect());
+    let options = Arc::new(options.clone());
+    let cache = Arc::new(cache.cloned());
+    let next = Arc::new(AtomicUsize::new(0));
+    let (tx, rx) = mpsc::channel();
+
+    for _ in 0..jobs.min(paths.len()) {
+        let (paths, options, cache, next, tx) = (paths.clone(), options.clone(), cache.clone(), next.clone(), tx.clone());
+        thread::spawn(move || {
+            loop {
+                let index = next.fetch_add(1, Ordering::Relaxed);
+                let Some(path) = paths.get(index) else {
+                    return;
+                };
+                if tx.send((index, get_ocr_results(path, frame, &options, cache.as_ref().as_ref(), timeout))).is_err() {
+                    return;
                 }
             }
-        }
-    } else if args.ocr {
-        for file in args.files {
-            if is_image(&file) {
-                if let Ok(ocr_result) = get_ocr_result(&file) {
-                    if let Some(stem) = Path::new(&file).file_stem().and_then(|s| s.to_str()) {
-                        let text_file = format!("{}{}", stem, ".txt");
-                        if let Ok(_) = export_text_file(&ocr_result.text, &text_file) {
-                            println!("{} --> {}", file, text_file);
-                        }
-                    }
-                }
+        });
+    }
+
+    OrderedResults { rx, ready: BTreeMap::new(), next: 0 }
+}
+
+// Reorders the results sent by the OCR threads back into input order
+struct OrderedResults {
+    rx: mpsc::Receiver<(usize, Result<Vec<OCRResult>, OcrError>)>,
+    ready: BTreeMap<usize, Result<Vec<OCRResult>, OcrError>>,
+    next: usize,
+}
+
+impl Iterator for OrderedResults {
+    type Item = Result<Vec<OCRResult>, OcrError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(result) = self.ready.remove(&self.next) {
+                self.next += 1;
+                return Some(result);
             }
+            let (index, result) = self.rx.recv().ok()?;
+            self.ready.insert(index, result);
         }
     }
+}
 
-    if args.server {
-        tracing_subscriber::registry()
-            .with(
-                tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| {
-                    format!("{}=debug,tower_http=debug", env!("CARGO_CRATE_NAME")).into()
-                }),
-            )
-            .with(tracing_subscriber::fmt::layer())
-            .init();
-
-        let mut stdout = StandardStream::stdout(ColorChoice::Always);
-
-        let upload_dir = std::env::temp_dir().join(UPLOAD_DIR_NAME);
-        std::fs::create_dir_all(&upload_dir).unwrap();
-
-        let app = Router::new()
-        .route("/", get(show_form))
-        .route("/upload", post(upload_file))
-        .layer(DefaultBodyLimit::disable())
-        .layer(RequestBodyLimitLayer::new(
-            100 * 1024 * 1024, /* 100mb */
-        ))
-        .layer(
-            TraceLayer::new_for_http()
-                .on_request(
-                    DefaultOnRequest::new()
-                        .level(Level::INFO)
-                )
-                .on_response(
-                    DefaultOnResponse::new()
-                        .level(Level::INFO)
-                        .latency_unit(tower_http::LatencyUnit::Millis),
-                )
-                .on_failure(
-                    DefaultOnFailure::new()
-                        .level(Level::ERROR)
-                )
-        );
-
-        let app = if !args.auth.is_empty() && is_valid_auth_format(&args.auth) {
-            print!("      Auth: ");
-            stdout.set_color(ColorSpec::new().set_fg(Some(Color::Blue)).set_bold(true)).unwrap();
-            writeln!(&mut stdout, "{}", args.auth).unwrap();
-            stdout.reset().unwrap();
-
-            let (username, password) = args.auth.split_once(':').unwrap();
-            let username = username.to_string();
-            let password = password.to_string();
-
-            app.layer(middleware::from_fn(move |headers, request, next| {
-                basic_auth_middleware_with_params(headers, request, next, username.clone(), password.clone())
-            }))
+// An input file, with the input directory it was found in
+struct InputFile {
+    path: String,
+    root: Option<PathBuf>,
+}
+
+impl InputFile {
+    fn new(path: String, root: Option<PathBuf>) -> Self {
+        InputFile { path, root }
+    }
+
+    // Path without extension used to name exported files. Images found in a directory are
+    // exported next to the image, or with --output-dir under their path relative to the
+    // directory. Other inputs use the file stem ("stdin" for "-") in the output directory.
+    fn export_stem(&self, output_dir: Option<&Path>) -> Option<String> {
+        let path = Path::new(&self.path);
+        let stem = if self.path == STDIN_INPUT {
+            PathBuf::from("stdin")
+        } else if let Some(root) = &self.root {
+            match output_dir {
+                Some(_) => path.strip_prefix(root).ok()?.with_extension(""),
+                None => path.with_extension(""),
+            }
         } else {
-            app
+            PathBuf::from(path.file_stem()?)
+        };
+
+        let stem = match output_dir {
+            Some(output_dir) => output_dir.join(stem),
+            None => stem,
         };
+        stem.to_str().map(String::from)
+    }
+}
 
-        let addr = format!("0.0.0.0:{}", args.port.to_string());
-
-        print!("   Address: ");
-        stdout.set_color(ColorSpec::new().set_fg(Some(Color::Blue)).set_bold(true)).unwrap();
-        writeln!(&mut stdout, "http://{}", addr).unwrap();
-        stdout.reset().unwrap();
-
-        print!("Upload dir: ");
-        stdout.set_color(ColorSpec::new().set_fg(Some(Color::Blue)).set_bold(true)).unwrap();
-        writeln!(&mut stdout, "{}", upload_dir.to_str().unwrap()).unwrap();
-        stdout.reset().unwrap();
-        println!("");
-                
-        let listener = tokio::net::TcpListener::bind(&addr).await.unwrap();
-        axum::serve(listener, app).await.unwrap();
-    }
-}
-
-fn is_image(path: &str) -> bool {
-    let data = fs::read(path);
-    match data {
-        Ok(data) => infer::is_image(&data),
-        Err(_) => false
-    }
-}
-
-fn get_ocr_result(path: &str) -> io::Result<OCRResult> {
-    let bytes = fs::read(path)?;
-    let data = NSData::with_bytes(&bytes);
-
-    let mut width: u32 = 0;
-    let mut height: u32 = 0;
-    if let Ok(img) = image::open(path) {
-        (width, height) = img.dimensions();
-    }
-
-    let request = VNRecognizeTextRequest::new();
-    unsafe { request.setRevision(VNRecognizeTextRequestRevision3) };
-    request.setRecognitionLevel(VNRequestTextRecognitionLevel::Accurate);
-    request.setUsesLanguageCorrection(true);
-    request.setAutomaticallyDetectsLanguage(true);
-
-    let request_super: Retained<VNRequest> = unsafe { Retained::cast_unchecked(request.clone()) };
-    let requests = NSArray::from_retained_slice(&[
-        request_super
-    ]);
-
-    let options = NSDictionary::new();
-    let handler = VNImageRequestHandler::initWithData_options(
-        VNImageRequestHandler::alloc(), &data, &options
-    );
-        
-    let _ = handler.performRequests_error(&requests);
-    
-    let mut items: Vec<OCRBoxItem> = Vec::new();
-    let mut result = String::new();
-    if let Some(observations) = request.results() {
-        for observation in observations {
-            if let Some(candidate) = observation.topCandidates(1).firstObject() {
-                let text = format!("{}", candidate.string());
-                result.push_str(&format!("{}\n", candidate.string()));
-
-                let corners = unsafe {[
-                    CGPoint{ x: observation.topLeft().x * width as f64, y: (1.0 - observation.topLeft().y) * height as f64 },
-                    CGPoint{ x: observation.topRight().x * width as f64, y: (1.0 - observation.topRight().y) * height as f64 },
-                    CGPoint{ x: observation.bottomRight().x * width as f64, y: (1.0 - observation.bottomRight().y) * height as f64 },
-                    CGPoint{ x: observation.bottomLeft().x  * width as f64, y: (1.0 - observation.bottomLeft().y)  * height as f64 }
-                ]};
-
-                let min_x = corners.iter().map(|p| p.x).fold(f64::INFINITY, f64::min);
-                let max_x = corners.iter().map(|p| p.x).fold(f64::NEG_INFINITY, f64::max);
-                let min_y = corners.iter().map(|p| p.y).fold(f64::INFINITY, f64::min);
-                let max_y = corners.iter().map(|p| p.y).fold(f64::NEG_INFINITY, f64::max);
-                
-                let rect_x = min_x;
-                let rect_y = min_y;
-                let rect_w = max_x - min_x;
-                let rect_h = max_y - min_y;
-
-                let rect = OCRRectItem::new(corners[0].x, corners[0].y, 
-                                                         corners[1].x, corners[1].y, 
-                                                         corners[2].x, corners[2].y, 
-                                                         corners[3].x, corners[3].y);
-
-                items.push(OCRBoxItem::new(text, rect_x, rect_y, rect_w, rect_h, rect));
+// Replace input directories by the images they contain, in path order
+fn expand_inputs(paths: &[String], recursive: bool) -> Vec<InputFile> {
+    let mut files = Vec::new();
+    for path in paths {
+        if Path::new(path).is_dir() {
+            let mut images = Vec::new();
+            if let Err(e) = collect_images(Path::new(path), recursive, &mut images) {
+                eprintln!("Failed to read {}: {}", path, e);
             }
+            images.sort();
+            files.extend(images.into_iter().map(|image| InputFile::new(image, Some(PathBuf::from(path)))));
+        } else {
+            files.push(InputFile::new(path.clone(), None));
         }
     }
+    files
+}
+
+fn collect_images(dir: &Path, recursive: bool, images: &mut Vec<String>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            if recursive {
+                collect_images(&path, recursive, images)?;
+            }
+        } else if let Some(path) = path.to_str() && is_ocr_input(path) {
+            images.push(path.to_string());
+        }
+    }
+    Ok(())
+}
+
+// Warn on stderr about requested languages that Vision can't recognize
+fn warn_unsupported_languages(options: &OCROptions) {
+    if options.languages.is_empty() {
+        return;
+    }
 
-    let ocr_result = OCRResult::new(
-        result,
-        width,
-        height,
-        items
-    );
+    let Ok(supported) = supported_languages(options) else {
+        return;
+    };
 
-    Ok(ocr_result)
+    for language in &options.languages {
+        if !supported.contains(language) {
+            eprintln!("Warning: unsupported recognition language: {}", language);
+        }
+    }
 }
 
-fn export_text_file(contents: &str, path: &str) -> io::Result<()> {
-    fs::write(path, contents)?;
-    Ok(())
+// The requested recognition revision, or the highest supported one with a warning if it's unavailable
+fn supported_revision(requested: usize) -> usize {
+    let supported = supported_revisions();
+    if supported.contains(&requested) {
+        return requested;
+    }
+    let Some(&highest) = supported.last() else {
+        return requested;
+    };
+    eprintln!("Warning: unsupported recognition revision: {}, using revision {}", requested, highest);
+    highest
 }
 
-// Show file upload form
-async fn show_form() -> Html<String> {
-    let html = format!(
-        r#"
-        <!doctype html>
-        <html>
-        <head>
-            <meta charset="utf-8">
-            <meta name="viewport" content="width=device-width, initial-scale=1.0">
-            <title>macocr</title>
-        </head>
-        <body>
-            <h1>macocr v{}</h1>
-            <form action="/upload" method="post" enctype="multipart/form-data">
-                <label>
-                    Choose file: 
-                    <input type="file" name="file" required>
-                </label>
-                <br><br>
-                <input type="submit" value="Upload file">
-            </form>
-        </body>
-        </html>
-        "#, 
-        VERSION
-    );
-    Html(html)
-}
-
-// Handle single file upload – supports HTML and JSON responses
-async fn upload_file(headers: HeaderMap, mut multipart: Multipart) -> impl IntoResponse {
-    // Determine if the request is an API request (based on the Accept header)
-    let is_api_request = headers.get("accept")
-        .and_then(|v| v.to_str().ok())
-        .map(|accept| accept.contains("application/json"))
-        .unwrap_or(false);
-    
-    // Get the first field
-    if let Some(field) = multipart.next_field().await.unwrap() {
-        let original_name = field.file_name().unwrap_or("unnamed").to_string();
-        let data = field.bytes().await.unwrap();
-        
-        // Generate a random filename while preserving the original file extension
-        let file_extension = std::path::Path::new(&original_name)
-            .extension()
-            .and_then(|ext| ext.to_str())
-            .unwrap_or("");
-        
-        let random_name = if file_extension.is_empty() {
-            Uuid::new_v4().to_string()
-        } else {
-            format!("{}.{}", Uuid::new_v4(), file_extension)
-        };
-        
-        // Generate a storage path under the system temporary directory
-        let upload_dir = std::env::temp_dir().join(UPLOAD_DIR_NAME);
-        let save_path = upload_dir.join(&random_name);
-        
-        // Write to file
-        match std::fs::File::create(&save_path) {
-            Ok(mut file) => {
-                match file.write_all(&data) {
-                    Ok(_) => {
-                        let mut success = false;
-                        let mut title = "❌ The file type is not an image".to_string();
-                        let mut message = "The file type is not an image".to_string();
-                        let mut ocr_result_text= "".to_string();
-                        let mut image_width = 0;
-                        let mut image_height = 0;
-                        let mut ocr_boxes = Vec::new();
-
-                        if let Some(path_str) = save_path.to_str() {
-                            if is_image(&path_str) {
-                                if let Ok(ocr_result) = get_ocr_result(&path_str) {
-                                    ocr_result_text = ocr_result.text;
-                                    image_width = ocr_result.image_width;
-                                    image_height = ocr_result.image_height;
-                                    ocr_boxes = ocr_result.boxes;
-                                    message = "File uploaded successfully".to_string();
-                                    title = "OCR Result:".to_string();
-                                    success = true;
-                                }
-                            } 
-                        } 
-                    
-                        if is_api_request {
-                            Json(UploadResponse {
-                                success: success,
-                                message: message.to_string(),
-                                ocr_result: ocr_result_text,
-                                image_width: image_width,
-                                image_height: image_height,
-                                ocr_boxes: ocr_boxes
-                            }).into_response()
-                        } else {
-                            Html(format!(
-                                r#"
-                                <!doctype html>
-                                <html>
-                                <head>
-                                    <meta charset="utf-8">
-                                    <meta name="viewport" content="width=device-width, initial-scale=1.0">
-                                    <title>OCR Result</title>
-                                </head>
-                                <body>
-                                    <h1>{}</h1>
-                                    <pre>{}</pre>
-                                </body>
-                                </html>
-                                "#,
-                                title, ocr_result_text.replace("&", "&amp;").replace("<", "&lt;").replace(">", "&gt;")
-                            )).into_response()
-                        }
-                    }
-                    Err(_) => {
-                        if is_api_request {
-                            Json(UploadResponse {
-                                success: false,
-                                message: "Failed to write file".to_string(),
-                                ocr_result: "".to_string(),
-                                image_width: 0,
-                                image_height: 0,
-                                ocr_boxes: Vec::new()
-                            }).into_response()
-                        } else {
-                            Html(r#"
-                                <!doctype html>
-                                <head>
-                                    <meta charset="utf-8">
-                                    <meta name="viewport" content="width=device-width, initial-scale=1.0">
-                                    <title>Error</title>
-                                </head>
-                                <html><body>
-                                    <h1>❌ Failed to write file.</h1>
-                                </body></html>
-                            "#.to_string()).into_response()
-                        }
-                    }
+// Print the supported recognition languages grouped by recognition level
+fn list_languages(options: &OCROptions) {
+    println!("Revision: {}", options.revision.unwrap_or_else(recognition_revision));
+    let supported: Vec<String> = supported_revisions().iter().map(usize::to_string).collect();
+    println!("Supported revisions: {}", supported.join(", "));
+
+    for level in [RecognitionLevel::Fast, RecognitionLevel::Accurate] {
+        println!();
+        if let Some(name) = level.to_possible_value() {
+            println!("{}:", name.get_name());
+        }
+        match supported_languages(&OCROptions { level, ..options.clone() }) {
+            Ok(languages) => {
+                for language in languages {
+                    println!("  {}", language);
                 }
             }
-            Err(_) => {
-                if is_api_request {
-                    Json(UploadResponse {
-                        success: false,
-                        message: "Unable to create file".to_string(),
-                        ocr_result: "".to_string(),
-                        image_width: 0,
-                        image_height: 0,
-                        ocr_boxes: Vec::new()
-                    }).into_response()
-                } else {
-                    Html(r#"
-                        <!doctype html>
-                        <head>
-                            <meta charset="utf-8">
-                            <meta name="viewport" content="width=device-width, initial-scale=1.0">
-                            <title>Error</title>
-                        </head>
-                        <html><body>
-                            <h1>❌ Unable to create file.</h1>
-                        </body></html>
-                    "#.to_string()).into_response()
-                }
+            Err(error) => eprintln!("  {}", error),
+        }
+    }
+}
+
+// Progress bar of --ocr on stderr, hidden with --quiet or when stderr isn't a terminal
+fn new_progress_bar(args: &Args, len: usize) -> ProgressBar {
+    if args.quiet || !io::stderr().is_terminal() {
+        return ProgressBar::hidden();
+    }
+    let style = ProgressStyle::with_template("{bar:40} {pos}/{len} {wide_msg}").unwrap();
+    ProgressBar::new(len as u64).with_style(style)
+}
+
+// Export the OCR results of an input to {stem}.{ext}, or to one {stem}.{lang}.txt per language
+fn export_results(args: &Args, file: &str, stem: &str, ocr_results: &[OCRResult]) {
+    if args.split_by_language {
+        for (language, text) in split_by_language(ocr_results) {
+            let text_file = format!("{}.{}.txt", stem, language);
+            if can_export_to(args, &text_file) {
+                report_export(args, file, &text_file, export_text_file(text.as_bytes(), &text_file));
             }
         }
     } else {
-        if is_api_request {
-            Json(UploadResponse {
-                success: false,
-                message: "No file received".to_string(),
-                ocr_result: "".to_string(),
-                image_width: 0,
-                image_height: 0,
-                ocr_boxes: Vec::new()
-            }).into_response()
-        } else {
-            Html(r#"
-                <!doctype html>
-                <head>
-                    <meta charset="utf-8">
-                    <meta name="viewport" content="width=device-width, initial-scale=1.0">
-                    <title>Error</title>
-                </head>
-                <html><body>
-                    <h1>❌ No file received</h1>
-                </body></html>
-            "#.to_string()).into_response()
+        let text_file = format!("{}.{}", stem, args.format.extension());
+        if can_export_to(args, &text_file) {
+            report_export(args, file, &text_file, export_text_file(&render_output(file, ocr_results, args.format), &text_file));
         }
     }
 }
 
-fn is_valid_auth_format(input: &str) -> bool {
-    let re = Regex::new(r"^[^:]+:[^:]+$").unwrap();
-    re.is_match(input)
-}
-
-// Basic Auth middleware
-async fn basic_auth_middleware_with_params(
-    headers: HeaderMap,
-    request: Request,
-    next: Next,
-    username: String,
-    password: String,
-) -> Result<Response, StatusCode> {
-    if let Some(auth_header) = headers.get("authorization") {
-        if let Ok(auth_str) = auth_header.to_str() {
-            if auth_str.starts_with("Basic ") {
-                let encoded = &auth_str[6..]; // Remove the 'Basic ' prefix
-                if let Ok(decoded_bytes) = general_purpose::STANDARD.decode(encoded) {
-                    if let Ok(decoded_str) = String::from_utf8(decoded_bytes) {
-                        // Split the username and password
-                        if let Some((user, pass)) = decoded_str.split_once(':') {
-                            if user == username && pass == password {
-                                // Authentication successful, proceeding with the request
-                                return Ok(next.run(request).await);
-                            }
-                        }
-                    }
-                }
+// Print every input with whether it's an image (or PDF) and where its output would be written,
+// returning whether any input isn't one. Stdin and URLs aren't read, so they aren't checked.
+fn dry_run(args: &Args, files: &[InputFile]) -> bool {
+    let mut failed = false;
+    for input in files {
+        let file = input.path.as_str();
+        let kind = if file == STDIN_INPUT || is_url(file) {
+            "not checked"
+        } else if is_ocr_input(file) {
+            "image"
+        } else {
+            failed = true;
+            println!("{}: not an image or PDF", file);
+            continue;
+        };
+
+        let output = if args.combine {
+            args.output.clone().filter(|output| output != STDOUT_OUTPUT).unwrap_or_else(|| "stdout".to_string())
+        } else if args.ocr {
+            let Some(stem) = input.export_stem(args.output_dir.as_deref().map(Path::new)) else {
+                failed = true;
+                println!("{}: {}, no output path", file, kind);
+                continue;
+            };
+            let text_file = if args.split_by_language {
+                format!("{}.{{lang}}.txt", stem)
+            } else {
+                format!("{}.{}", stem, args.format.extension())
+            };
+            if args.no_clobber && Path::new(&text_file).exists() {
+                format!("{} (exists, skipped)", text_file)
+            } else {
+                text_file
             }
+        } else if args.copy {
+            "clipboard".to_string()
+        } else {
+            args.append_to.clone().unwrap_or_else(|| "stdout".to_string())
+        };
+        println!("{}: {} --> {}", file, kind, output);
+    }
+    failed
+}
+
+// Print the "input --> exported file" line unless --quiet is given, or why the export failed
+fn report_export(args: &Args, file: &str, text_file: &str, result: io::Result<()>) {
+    match result {
+        Ok(()) if !args.quiet => println!("{} --> {}", file, text_file),
+        Ok(()) => {}
+        Err(e) => eprintln!("Failed to write {}: {}", text_file, e),
+    }
+}
+
+// Report on stderr why an input couldn't be OCR'd, exiting right away with --strict, and return
+// whether it counts as a failure. Images under --min-dimension are only skipped with a warning.
+fn report_failure(args: &Args, file: &str, e: &OcrError) -> bool {
+    if let OcrError::ImageTooSmall(..) = e {
+        eprintln!("Warning: skipping {}: {}", file, e);
+        return false;
+    }
+    eprintln!("{}: {}", file, e);
+    if args.strict {
+        std::process::exit(1);
+    }
+    true
+}
+
+// With --verbose, report on stderr that an input was OCR'd
+fn report_success(args: &Args, file: &str, ocr_results: &[OCRResult]) {
+    if args.verbose {
+        let boxes: usize = ocr_results.iter().map(|r| r.boxes.len()).sum();
+        eprintln!("{}: OK ({} text boxes)", file, boxes);
+    }
+}
+
+// Open the output of --combine: stdout by default or for "-", otherwise a new file
+fn open_combined_output(path: Option<&str>) -> io::Result<Box<dyn Write>> {
+    match path {
+        Some(path) if path != STDOUT_OUTPUT => Ok(Box::new(io::BufWriter::new(fs::File::create(path)?))),
+        _ => Ok(Box::new(io::stdout())),
+    }
+}
+
+// Check whether an exported file may be written, warning about existing files unless --force is given
+fn can_export_to(args: &Args, path: &str) -> bool {
+    if args.force || !Path::new(path).exists() {
+        true
+    } else if args.no_clobber {
+        eprintln!("Skipping {}: file already exists", path);
+        false
+    } else {
+        eprintln!("Overwriting {}", path);
+        true
+    }
+}
+
+// Print to stdout, or append to the given file
+fn write_output(output: &[u8], append_to: Option<&str>) {
+    if let Some(append_to) = append_to {
+        if let Err(e) = append_to_file(output, append_to) {
+            eprintln!("Failed to append to {}: {}", append_to, e);
         }
+    } else {
+        let _ = io::stdout().write_all(output);
     }
+}
+
+fn export_text_file(contents: &[u8], path: &str) -> io::Result<()> {
+    if let Some(parent) = Path::new(path).parent() && !parent.as_os_str().is_empty() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, contents)?;
+    Ok(())
+}
+
+// Copy an output to the clipboard
+fn copy_output(output: &[u8]) {
+    if !clipboard::write_text(&String::from_utf8_lossy(output)) {
+        eprintln!("Failed to copy the output to the clipboard");
+        std::process::exit(1);
+    }
+}
 
-    // Authentication failed, return 401 and request authentication
-    let mut response = Response::new("Authentication failed: A valid username and password are required.".into());
-    *response.status_mut() = StatusCode::UNAUTHORIZED;
-    response.headers_mut().insert(
-        "WWW-Authenticate",
-        "Basic realm=\"MacOCR Server\"".parse().unwrap(),
-    );
-    
-    Ok(response)
+// Append to a file under an exclusive lock, so concurrent runs never interleave lines
+fn append_to_file(contents: &[u8], path: &str) -> io::Result<()> {
+    let mut file = fs::OpenOptions::new().create(true).append(true).open(path)?;
+    file.lock()?;
+    file.write_all(contents)?;
+    file.flush()?;
+    file.unlock()
 }
@@ -0,0 +1,88 @@
+use std::{fmt::Write, sync::atomic::{AtomicU64, Ordering}, time::{Duration, Instant}};
+
+// upper bounds (seconds) of the OCR latency histogram buckets
+const LATENCY_BUCKETS: [f64; 9] = [0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
+// Counters of the HTTP server, rendered in the Prometheus text format
+pub struct Metrics {
+    api_uploads: AtomicU64,
+    html_uploads: AtomicU64,
+    ocr_successes: AtomicU64,
+    ocr_failures: AtomicU64,
+    latency_buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    latency_count: AtomicU64,
+    latency_sum_micros: AtomicU64,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics {
+            api_uploads: AtomicU64::new(0),
+            html_uploads: AtomicU64::new(0),
+            ocr_successes: AtomicU64::new(0),
+            ocr_failures: AtomicU64::new(0),
+            latency_buckets: std::array::from_fn(|_| AtomicU64::new(0)),
+            latency_count: AtomicU64::new(0),
+            latency_sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    // Count an upload request, by whether it asked for JSON (API) or HTML (browser)
+    pub fn record_upload(&self, is_api_request: bool) {
+        let counter = if is_api_request { &self.api_uploads } else { &self.html_uploads };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Run an OCR call, counting its outcome and observing its latency
+    pub fn observe_ocr<T, E>(&self, ocr: impl FnOnce() -> Result<T, E>) -> Result<T, E> {
+        let start = Instant::now();
+        let result = ocr();
+        self.record_latency(start.elapsed());
+
+        let counter = if result.is_ok() { &self.ocr_successes } else { &self.ocr_failures };
+        counter.fetch_add(1, Ordering::Relaxed);
+        result
+    }
+
+    fn record_latency(&self, latency: Duration) {
+        // The count goes first so that a scrape never sees a bucket above it
+        self.latency_count.fetch_add(1, Ordering::Relaxed);
+        self.latency_sum_micros.fetch_add(latency.as_micros() as u64, Ordering::Relaxed);
+
+        let seconds = latency.as_secs_f64();
+        // Buckets are cumulative: every bucket with a bound above the latency counts it
+        for (bound, bucket) in LATENCY_BUCKETS.iter().zip(&self.latency_buckets) {
+            if seconds <= *bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    // Render all metrics in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        out.push_str("# HELP macocr_uploads_total Upload requests, by API (JSON) or browser (HTML) client.\n");
+        out.push_str("# TYPE macocr_uploads_total counter\n");
+        writeln!(out, "macocr_uploads_total{{client=\"api\"}} {}", self.api_uploads.load(Ordering::Relaxed)).unwrap();
+        writeln!(out, "macocr_uploads_total{{client=\"html\"}} {}", self.html_uploads.load(Ordering::Relaxed)).unwrap();
+
+        out.push_str("# HELP macocr_ocr_total OCR runs, by outcome.\n");
+        out.push_str("# TYPE macocr_ocr_total counter\n");
+        writeln!(out, "macocr_ocr_total{{result=\"success\"}} {}", self.ocr_successes.load(Ordering::Relaxed)).unwrap();
+        writeln!(out, "macocr_ocr_total{{result=\"failure\"}} {}", self.ocr_failures.load(Ordering::Relaxed)).unwrap();
+
+        out.push_str("# HELP macocr_ocr_duration_seconds Latency of OCR runs.\n");
+        out.push_str("# TYPE macocr_ocr_duration_seconds histogram\n");
+        for (bound, bucket) in LATENCY_BUCKETS.iter().zip(&self.latency_buckets) {
+            writeln!(out, "macocr_ocr_duration_seconds_bucket{{le=\"{}\"}} {}", bound, bucket.load(Ordering::Relaxed)).unwrap();
+        }
+        let count = self.latency_count.load(Ordering::Relaxed);
+        writeln!(out, "macocr_ocr_duration_seconds_bucket{{le=\"+Inf\"}} {}", count).unwrap();
+        let sum = self.latency_sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        writeln!(out, "macocr_ocr_duration_seconds_sum {}", sum).unwrap();
+        writeln!(out, "macocr_ocr_duration_seconds_count {}", count).unwrap();
+
+        out
+    }
+}
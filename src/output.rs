@@ -0,0 +1,652 @@
+//! Renderers for the output formats of the CLI
+
+use clap::ValueEnum;
+use image::{GenericImageView, ImageFormat};
+use serde::Serialize;
+use std::{collections::BTreeMap, io::Cursor};
+use crate::{OCRResult, TextDirection, VERSION};
+
+/// Output format of the CLI
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum OutputFormat {
+    /// Plain text
+    Text,
+    /// JSON lines with parallel texts/boxes/confidences arrays
+    Columns,
+    /// JSON with the file path and the full OCR result (an array for several files)
+    Json,
+    /// JSON lines with the file path and the full OCR result
+    Jsonl,
+    /// hOCR (XHTML) document
+    Hocr,
+    /// ALTO 4 XML document
+    Alto,
+    /// Searchable PDF: the image with an invisible, selectable text layer
+    Pdf,
+    /// CSV with one text,x,y,w,h row per box (plus a file column for several files)
+    Csv,
+    /// COCO JSON annotations with one image per result and one "text" annotation per box
+    Coco,
+    /// JSON object of the "Label: value" lines, with the other lines under "_unlabeled"
+    Kv,
+    /// JSON rows × columns grid of the table detected in the boxes
+    Table,
+    /// CSV rows of the table detected in the boxes (plus a file column for several files)
+    TableCsv,
+}
+
+impl OutputFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::Text => "txt",
+            OutputFormat::Columns => "json",
+            OutputFormat::Json => "json",
+            OutputFormat::Jsonl => "jsonl",
+            OutputFormat::Hocr => "hocr",
+            OutputFormat::Alto => "xml",
+            OutputFormat::Pdf => "pdf",
+            OutputFormat::Csv => "csv",
+            OutputFormat::Coco => "json",
+            OutputFormat::Kv => "json",
+            OutputFormat::Table => "json",
+            OutputFormat::TableCsv => "csv",
+        }
+    }
+}
+
+/// Subtitle format of the cues OCR'd from video frames
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum SubtitleFormat {
+    /// SubRip (.srt)
+    Srt,
+    /// WebVTT (.vtt)
+    Vtt,
+}
+
+/// Column-oriented OCR result: boxes transposed into parallel arrays
+#[derive(Serialize)]
+pub struct OCRColumns<'a> {
+    image_width: u32,
+    image_height: u32,
+    texts: Vec<&'a str>,
+    boxes: Vec<[f64; 4]>,
+    confidences: Vec<f32>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    directions: Vec<TextDirection>,
+}
+
+impl<'a> From<&'a OCRResult> for OCRColumns<'a> {
+    fn from(ocr_result: &'a OCRResult) -> Self {
+        OCRColumns {
+            image_width: ocr_result.image_width,
+            image_height: ocr_result.image_height,
+            texts: ocr_result.boxes.iter().map(|item| item.text.as_str()).collect(),
+            boxes: ocr_result.boxes.iter().map(|item| [item.x, item.y, item.w, item.h]).collect(),
+            confidences: ocr_result.boxes.iter().map(|item| item.confidence).collect(),
+            directions: ocr_result.boxes.iter().filter_map(|item| item.direction).collect(),
+        }
+    }
+}
+
+/// OCR result of an input file (or of one frame of it) in the JSON outputs
+#[derive(Serialize)]
+pub struct OCRFileResult<'a> {
+    file: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    frame: Option<usize>,
+    #[serde(flatten)]
+    result: &'a OCRResult,
+}
+
+/// Render the OCR results of one input (one per frame) in the given format
+pub fn render_output(file: &str, ocr_results: &[OCRResult], format: OutputFormat) -> Vec<u8> {
+    let output: String = match format {
+        OutputFormat::Text => ocr_results.iter().map(|r| r.text.as_str()).collect(),
+        OutputFormat::Columns => ocr_results.iter()
+            .map(|r| format!("{}\n", serde_json::to_string(&OCRColumns::from(r)).unwrap()))
+            .collect(),
+        OutputFormat::Json => render_json(&file_results(file, ocr_results), false),
+        OutputFormat::Jsonl => file_results(file, ocr_results).iter()
+            .map(|entry| format!("{}\n", serde_json::to_string(entry).unwrap()))
+            .collect(),
+        OutputFormat::Hocr => render_hocr(file, ocr_results),
+        OutputFormat::Alto => render_alto(file, ocr_results),
+        OutputFormat::Pdf => return render_pdf(ocr_results),
+        OutputFormat::Csv => render_csv([(file, ocr_results)], false),
+        OutputFormat::Coco => render_coco([(file, ocr_results)]),
+        OutputFormat::Kv => render_kv([(file, ocr_results)], false),
+        OutputFormat::Table => render_table_json([(file, ocr_results)], false),
+        OutputFormat::TableCsv => render_table_csv([(file, ocr_results)], false),
+    };
+    output.into_bytes()
+}
+
+/// Pair each OCR result with its file, adding frame indexes for animated inputs
+pub fn file_results<'a>(file: &'a str, ocr_results: &'a [OCRResult]) -> Vec<OCRFileResult<'a>> {
+    ocr_results.iter()
+        .enumerate()
+        .map(|(index, result)| {
+            let frame = if ocr_results.len() > 1 { Some(index) } else { None };
+            OCRFileResult { file, frame, result }
+        })
+        .collect()
+}
+
+/// A single result is rendered as a JSON object, several (or any number with `array`) as an array
+pub fn render_json(entries: &[OCRFileResult], array: bool) -> String {
+    let json = match entries {
+        [entry] if !array => serde_json::to_string_pretty(entry),
+        _ => serde_json::to_string_pretty(entries),
+    };
+    format!("{}\n", json.unwrap())
+}
+
+/// Render one CSV row per box with a header row, optionally prefixed with the file column
+pub fn render_csv<'a>(inputs: impl IntoIterator<Item = (&'a str, &'a [OCRResult])>, with_file: bool) -> String {
+    let mut csv = String::from(if with_file { "file,text,x,y,w,h\n" } else { "text,x,y,w,h\n" });
+    for (file, ocr_results) in inputs {
+        for item in ocr_results.iter().flat_map(|r| &r.boxes) {
+            if with_file {
+                csv.push_str(&escape_csv(file));
+                csv.push(',');
+            }
+            csv.push_str(&format!("{},{},{},{},{}\n", escape_csv(&item.text), item.x, item.y, item.w, item.h));
+        }
+    }
+    csv
+}
+
+// COCO dataset of the --format coco output
+#[derive(Serialize)]
+struct CocoDataset<'a> {
+    images: Vec<CocoImage<'a>>,
+    annotations: Vec<CocoAnnotation<'a>>,
+    categories: [CocoCategory; 1],
+}
+
+#[derive(Serialize)]
+struct CocoImage<'a> {
+    id: usize,
+    file_name: &'a str,
+    width: u32,
+    height: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    frame: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct CocoAnnotation<'a> {
+    id: usize,
+    image_id: usize,
+    category_id: usize,
+    bbox: [f64; 4],
+    area: f64,
+    iscrowd: u8,
+    attributes: CocoAttributes<'a>,
+}
+
+#[derive(Serialize)]
+struct CocoAttributes<'a> {
+    text: &'a str,
+    confidence: f32,
+}
+
+#[derive(Serialize)]
+struct CocoCategory {
+    id: usize,
+    name: &'static str,
+}
+
+// id of the only COCO category, for all text boxes
+const COCO_TEXT_CATEGORY: usize = 1;
+
+/// Render COCO JSON annotations: one image per OCR result (frames of an input share its file
+/// name) and one annotation per box, with the recognized text in its attributes. COCO ids start at 1.
+pub fn render_coco<'a>(inputs: impl IntoIterator<Item = (&'a str, &'a [OCRResult])>) -> String {
+    let mut dataset = CocoDataset {
+        images: Vec::new(),
+        annotations: Vec::new(),
+        categories: [CocoCategory { id: COCO_TEXT_CATEGORY, name: "text" }],
+    };
+    for (file, ocr_results) in inputs {
+        for (index, ocr_result) in ocr_results.iter().enumerate() {
+            let image_id = dataset.images.len() + 1;
+            dataset.images.push(CocoImage {
+                id: image_id,
+                file_name: file,
+                width: ocr_result.image_width,
+                height: ocr_result.image_height,
+                frame: if ocr_results.len() > 1 { Some(index) } else { None },
+            });
+            for item in &ocr_result.boxes {
+                dataset.annotations.push(CocoAnnotation {
+                    id: dataset.annotations.len() + 1,
+                    image_id,
+                    category_id: COCO_TEXT_CATEGORY,
+                    bbox: [item.x, item.y, item.w, item.h],
+                    area: item.w * item.h,
+                    iscrowd: 0,
+                    attributes: CocoAttributes { text: &item.text, confidence: item.confidence },
+                });
+            }
+        }
+    }
+    format!("{}\n", serde_json::to_string_pretty(&dataset).unwrap())
+}
+
+// Quote a CSV field when it contains a separator, quote or line break
+fn escape_csv(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Render an hOCR document with one ocr_page per result and one ocr_line per box.
+/// Vision only reports line boxes, so word boxes are split from the line box by character offset.
+pub fn render_hocr(file: &str, ocr_results: &[OCRResult]) -> String {
+    let mut hocr = format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE html PUBLIC "-//W3C//DTD XHTML 1.0 Transitional//EN" "http://www.w3.org/TR/xhtml1/DTD/xhtml1-transitional.dtd">
+<html xmlns="http://www.w3.org/1999/xhtml" xml:lang="en" lang="en">
+<head>
+  <title>{}</title>
+  <meta http-equiv="Content-Type" content="text/html; charset=utf-8" />
+  <meta name="ocr-system" content="macocr {}" />
+  <meta name="ocr-capabilities" content="ocr_page ocr_line ocrx_word" />
+</head>
+<body>
+"#,
+        escape_xml(file), VERSION
+    );
+
+    for (page, ocr_result) in ocr_results.iter().enumerate() {
+        let page = page + 1;
+        hocr.push_str(&format!(
+            "  <div class=\"ocr_page\" id=\"page_{}\" title=\"image &quot;{}&quot;; bbox 0 0 {} {}; ppageno {}\">\n",
+            page, escape_xml(file), ocr_result.image_width, ocr_result.image_height, page - 1
+        ));
+
+        for (line, item) in ocr_result.boxes.iter().enumerate() {
+            let line = line + 1;
+            hocr.push_str(&format!(
+                "    <span class=\"ocr_line\" id=\"line_{}_{}\" title=\"bbox {} {} {} {}\">",
+                page, line, item.x.round(), item.y.round(), (item.x + item.w).round(), (item.y + item.h).round()
+            ));
+
+            let chars = item.text.chars().count().max(1) as f64;
+            let mut offset = 0;
+            let mut words = Vec::new();
+            for (word_index, word) in item.text.split(' ').enumerate() {
+                let len = word.chars().count();
+                if len > 0 {
+                    let x0 = item.x + item.w * offset as f64 / chars;
+                    let x1 = item.x + item.w * (offset + len) as f64 / chars;
+                    words.push(format!(
+                        "<span class=\"ocrx_word\" id=\"word_{}_{}_{}\" title=\"bbox {} {} {} {}; x_wconf {}\">{}</span>",
+                        page, line, word_index + 1, x0.round(), item.y.round(), x1.round(), (item.y + item.h).round(),
+                        (item.confidence * 100.0).round(), escape_xml(word)
+                    ));
+                }
+                offset += len + 1;
+            }
+            hocr.push_str(&words.join(" "));
+            hocr.push_str("</span>\n");
+        }
+
+        hocr.push_str("  </div>\n");
+    }
+
+    hocr.push_str("</body>\n</html>\n");
+    hocr
+}
+
+/// Render an ALTO 4 document with one Page per result. Each box becomes a String
+/// wrapped in its own TextLine and TextBlock, with coordinates rounded to pixels.
+pub fn render_alto(file: &str, ocr_results: &[OCRResult]) -> String {
+    let mut alto = format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<alto xmlns="http://www.loc.gov/standards/alto/ns-v4#" xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance" xsi:schemaLocation="http://www.loc.gov/standards/alto/ns-v4# http://www.loc.gov/standards/alto/v4/alto-4-2.xsd">
+  <Description>
+    <MeasurementUnit>pixel</MeasurementUnit>
+    <sourceImageInformation>
+      <fileName>{}</fileName>
+    </sourceImageInformation>
+    <OCRProcessing ID="OCR_1">
+      <ocrProcessingStep>
+        <processingSoftware>
+          <softwareName>macocr</softwareName>
+          <softwareVersion>{}</softwareVersion>
+        </processingSoftware>
+      </ocrProcessingStep>
+    </OCRProcessing>
+  </Description>
+  <Layout>
+"#,
+        escape_xml(file), VERSION
+    );
+
+    for (page, ocr_result) in ocr_results.iter().enumerate() {
+        let page = page + 1;
+        alto.push_str(&format!(
+            "    <Page ID=\"PAGE_{0}\" PHYSICAL_IMG_NR=\"{0}\" WIDTH=\"{1}\" HEIGHT=\"{2}\">\n      <PrintSpace HPOS=\"0\" VPOS=\"0\" WIDTH=\"{1}\" HEIGHT=\"{2}\">\n",
+            page, ocr_result.image_width, ocr_result.image_height
+        ));
+
+        for (line, item) in ocr_result.boxes.iter().enumerate() {
+            let line = line + 1;
+            let position = format!(
+                "HPOS=\"{}\" VPOS=\"{}\" WIDTH=\"{}\" HEIGHT=\"{}\"",
+                item.x.round() as i64, item.y.round() as i64, item.w.round() as i64, item.h.round() as i64
+            );
+            alto.push_str(&format!(
+                "        <TextBlock ID=\"BLOCK_{0}_{1}\" {2}>\n          <TextLine ID=\"LINE_{0}_{1}\" {2}>\n            <String ID=\"STRING_{0}_{1}\" {2} CONTENT=\"{3}\" WC=\"{4:.2}\"/>\n          </TextLine>\n        </TextBlock>\n",
+                page, line, position, escape_xml(&item.text), item.confidence
+            ));
+        }
+
+        alto.push_str("      </PrintSpace>\n    </Page>\n");
+    }
+
+    alto.push_str("  </Layout>\n</alto>\n");
+    alto
+}
+
+/// Render an SVG overlay of the boxes of an OCR result, sized to the image in pixels so it can be
+/// layered over the image with CSS. Every box is a red `<rect>`, optionally labeled with its text.
+pub fn render_svg(ocr_result: &OCRResult, labels: bool) -> String {
+    let mut svg = format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{0}\" height=\"{1}\" viewBox=\"0 0 {0} {1}\">\n",
+        ocr_result.image_width, ocr_result.image_height
+    );
+    for item in &ocr_result.boxes {
+        svg.push_str(&format!(
+            "  <rect x=\"{:.1}\" y=\"{:.1}\" width=\"{:.1}\" height=\"{:.1}\" fill=\"none\" stroke=\"red\" stroke-width=\"2\"/>\n",
+            item.x, item.y, item.w, item.h
+        ));
+        if labels {
+            // Above the box, small enough not to cover the line above it
+            svg.push_str(&format!(
+                "  <text x=\"{:.1}\" y=\"{:.1}\" font-size=\"{:.1}\" fill=\"red\">{}</text>\n",
+                item.x, item.y - 2.0, (item.h / 2.0).max(8.0), escape_xml(&item.text)
+            ));
+        }
+    }
+    svg.push_str("</svg>\n");
+    svg
+}
+
+/// Render a searchable PDF with one page per result. The page shows the OCR'd image
+/// (1 px = 1 pt) and every box's text is drawn over it in invisible render mode 3,
+/// rotated and stretched to the box quad, so it can be selected and copied.
+pub fn render_pdf(ocr_results: &[OCRResult]) -> Vec<u8> {
+    let mut pdf = PdfWriter::new();
+    let catalog = pdf.reserve();
+    let pages = pdf.reserve();
+    let font = pdf.reserve();
+    let cid_font = pdf.reserve();
+    let descriptor = pdf.reserve();
+    let to_unicode = pdf.reserve();
+
+    // A glyphless CID font: character codes are UTF-16 code units, mapped back to Unicode by
+    // the ToUnicode CMap. The text is never painted, so no font program needs to be embedded.
+    pdf.object(font, format!(
+        "<< /Type /Font /Subtype /Type0 /BaseFont /GlyphLessFont /Encoding /Identity-H /DescendantFonts [{} 0 R] /ToUnicode {} 0 R >>",
+        cid_font, to_unicode
+    ).as_bytes());
+    pdf.object(cid_font, format!(
+        "<< /Type /Font /Subtype /CIDFontType2 /BaseFont /GlyphLessFont /CIDSystemInfo << /Registry (Adobe) /Ordering (Identity) /Supplement 0 >> /FontDescriptor {} 0 R /CIDToGIDMap /Identity /DW 500 >>",
+        descriptor
+    ).as_bytes());
+    pdf.object(descriptor, b"<< /Type /FontDescriptor /FontName /GlyphLessFont /Flags 5 /FontBBox [0 0 500 1000] /ItalicAngle 0 /Ascent 1000 /Descent 0 /CapHeight 1000 /StemV 80 >>");
+
+    let mut cmap = String::from("/CIDInit /ProcSet findresource begin\n12 dict begin\nbegincmap\n/CIDSystemInfo << /Registry (Adobe) /Ordering (UCS) /Supplement 0 >> def\n/CMapName /Adobe-Identity-UCS def\n/CMapType 2 def\n1 begincodespacerange\n<0000> <FFFF>\nendcodespacerange\n");
+    // bfrange entries may only differ in the last byte, so map each high byte separately
+    for chunk in (0..=0xFFu32).collect::<Vec<_>>().chunks(100) {
+        cmap.push_str(&format!("{} beginbfrange\n", chunk.len()));
+        for high in chunk {
+            cmap.push_str(&format!("<{0:02X}00> <{0:02X}FF> <{0:02X}00>\n", high));
+        }
+        cmap.push_str("endbfrange\n");
+    }
+    cmap.push_str("endcmap\nCMapName currentdict /CMap defineresource pop\nend\nend");
+    pdf.stream(to_unicode, "", cmap.as_bytes());
+
+    let mut kids = Vec::new();
+    for ocr_result in ocr_results {
+        let Some(img) = ocr_result.image.as_deref().and_then(|bytes| image::load_from_memory(bytes).ok()) else {
+            continue;
+        };
+        let (width, height) = img.dimensions();
+        let mut jpeg = Vec::new();
+        if img.to_rgb8().write_to(&mut Cursor::new(&mut jpeg), ImageFormat::Jpeg).is_err() {
+            continue;
+        }
+
+        let image = pdf.reserve();
+        pdf.stream(image, &format!(
+            "/Type /XObject /Subtype /Image /Width {} /Height {} /ColorSpace /DeviceRGB /BitsPerComponent 8 /Filter /DCTDecode",
+            width, height
+        ), &jpeg);
+
+        let page_height = height as f64;
+        let mut content = format!("q {} 0 0 {} 0 0 cm /Im1 Do Q\nBT 3 Tr\n", width, height);
+        for item in &ocr_result.boxes {
+            let rect = &item.rect;
+            // PDF space has its origin at the bottom-left corner
+            let (left_x, left_y) = (rect.bottom_left_x, page_height - rect.bottom_left_y);
+            let (dx, dy) = (rect.top_right_x - rect.top_left_x, rect.top_left_y - rect.top_right_y);
+            let box_width = dx.hypot(dy);
+            let box_height = (rect.top_left_x - rect.bottom_left_x).hypot(rect.top_left_y - rect.bottom_left_y);
+
+            let code_units: Vec<u16> = item.text.encode_utf16().collect();
+            if code_units.is_empty() || box_width <= 0.0 || box_height <= 0.0 {
+                continue;
+            }
+
+            // Every glyph is half an em wide, so stretch the text horizontally to the box width
+            let scaling = 100.0 * box_width / (code_units.len() as f64 * 0.5 * box_height);
+            let (cos, sin) = (dx / box_width, dy / box_width);
+            let hex: String = code_units.iter().map(|unit| format!("{:04X}", unit)).collect();
+            content.push_str(&format!(
+                "/F1 {:.2} Tf {:.2} Tz {:.4} {:.4} {:.4} {:.4} {:.2} {:.2} Tm <{}> Tj\n",
+                box_height, scaling, cos, sin, -sin, cos, left_x, left_y, hex
+            ));
+        }
+        content.push_str("ET");
+
+        let contents = pdf.reserve();
+        pdf.stream(contents, "", content.as_bytes());
+
+        let page = pdf.reserve();
+        pdf.object(page, format!(
+            "<< /Type /Page /Parent {} 0 R /MediaBox [0 0 {} {}] /Resources << /Font << /F1 {} 0 R >> /XObject << /Im1 {} 0 R >> >> /Contents {} 0 R >>",
+            pages, width, height, font, image, contents
+        ).as_bytes());
+        kids.push(format!("{} 0 R", page));
+    }
+
+    pdf.object(pages, format!("<< /Type /Pages /Kids [{}] /Count {} >>", kids.join(" "), kids.len()).as_bytes());
+    pdf.object(catalog, format!("<< /Type /Catalog /Pages {} 0 R >>", pages).as_bytes());
+    pdf.finish(catalog)
+}
+
+// Minimal PDF serializer: objects are reserved up front and written in any order
+struct PdfWriter {
+    buffer: Vec<u8>,
+    offsets: Vec<usize>,
+}
+
+impl PdfWriter {
+    fn new() -> Self {
+        PdfWriter { buffer: b"%PDF-1.4\n%\xE2\xE3\xCF\xD3\n".to_vec(), offsets: Vec::new() }
+    }
+
+    // Reserve the next object number
+    fn reserve(&mut self) -> usize {
+        self.offsets.push(0);
+        self.offsets.len()
+    }
+
+    fn object(&mut self, id: usize, body: &[u8]) {
+        self.offsets[id - 1] = self.buffer.len();
+        self.buffer.extend_from_slice(format!("{} 0 obj\n", id).as_bytes());
+        self.buffer.extend_from_slice(body);
+        self.buffer.extend_from_slice(b"\nendobj\n");
+    }
+
+    fn stream(&mut self, id: usize, dict: &str, data: &[u8]) {
+        let mut body = format!("<< {} /Length {} >>\nstream\n", dict, data.len()).into_bytes();
+        body.extend_from_slice(data);
+        body.extend_from_slice(b"\nendstream");
+        self.object(id, &body);
+    }
+
+    fn finish(mut self, root: usize) -> Vec<u8> {
+        let xref = self.buffer.len();
+        let mut trailer = format!("xref\n0 {}\n0000000000 65535 f \n", self.offsets.len() + 1);
+        for offset in &self.offsets {
+            trailer.push_str(&format!("{:010} 00000 n \n", offset));
+        }
+        trailer.push_str(&format!(
+            "trailer\n<< /Size {} /Root {} 0 R >>\nstartxref\n{}\n%%EOF\n",
+            self.offsets.len() + 1, root, xref
+        ));
+        self.buffer.extend_from_slice(trailer.as_bytes());
+        self.buffer
+    }
+}
+
+// Escape text for XML/XHTML content and attribute values
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+// key of the --format kv lines without a label
+const UNLABELED_KEY: &str = "_unlabeled";
+
+/// Render the "Label: value" lines of the text as a JSON object mapping labels to values (an
+/// array of values for repeated labels), with the lines without a colon in an "_unlabeled" array.
+/// With several files, the objects are keyed by file.
+pub fn render_kv<'a>(inputs: impl IntoIterator<Item = (&'a str, &'a [OCRResult])>, with_file: bool) -> String {
+    let mut json = serde_json::Map::new();
+    for (file, ocr_results) in inputs {
+        let fields = key_values(ocr_results.iter().flat_map(|r| r.text.lines()));
+        if with_file {
+            json.insert(file.to_string(), serde_json::Value::Object(fields));
+        } else {
+            json = fields;
+        }
+    }
+    format!("{}\n", serde_json::to_string_pretty(&json).unwrap())
+}
+
+// Split each line on its first (ASCII or full-width) colon into a label and a value
+fn key_values<'a>(lines: impl Iterator<Item = &'a str>) -> serde_json::Map<String, serde_json::Value> {
+    let mut fields = serde_json::Map::new();
+    let mut unlabeled = Vec::new();
+    for line in lines {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let Some((label, value)) = line.split_once([':', '：']).filter(|(label, _)| !label.trim().is_empty()) else {
+            unlabeled.push(serde_json::Value::from(line));
+            continue;
+        };
+        let value = serde_json::Value::from(value.trim());
+        match fields.get_mut(label.trim()) {
+            Some(serde_json::Value::Array(values)) => values.push(value),
+            Some(existing) => *existing = serde_json::Value::Array(vec![existing.take(), value]),
+            None => {
+                fields.insert(label.trim().to_string(), value);
+            }
+        }
+    }
+    if !unlabeled.is_empty() {
+        fields.insert(UNLABELED_KEY.to_string(), serde_json::Value::Array(unlabeled));
+    }
+    fields
+}
+
+/// Render the tables detected in the OCR results as a JSON array of rows (the rows of all the
+/// frames of an input follow each other). With several files, the grids are keyed by file.
+pub fn render_table_json<'a>(inputs: impl IntoIterator<Item = (&'a str, &'a [OCRResult])>, with_file: bool) -> String {
+    let mut tables: Vec<(&str, Vec<Vec<String>>)> = inputs.into_iter()
+        .map(|(file, ocr_results)| (file, ocr_results.iter().flat_map(OCRResult::table).collect()))
+        .collect();
+    let json = if with_file {
+        serde_json::Value::Object(tables.into_iter().map(|(file, rows)| (file.to_string(), rows.into())).collect())
+    } else {
+        tables.pop().map(|(_, rows)| rows).unwrap_or_default().into()
+    };
+    format!("{}\n", serde_json::to_string_pretty(&json).unwrap())
+}
+
+/// Render the tables detected in the OCR results as CSV rows, optionally prefixed with the file column
+pub fn render_table_csv<'a>(inputs: impl IntoIterator<Item = (&'a str, &'a [OCRResult])>, with_file: bool) -> String {
+    let mut csv = String::new();
+    for (file, ocr_results) in inputs {
+        for row in ocr_results.iter().flat_map(OCRResult::table) {
+            let mut fields: Vec<String> = row.iter().map(|cell| escape_csv(cell)).collect();
+            if with_file {
+                fields.insert(0, escape_csv(file));
+            }
+            csv.push_str(&fields.join(","));
+            csv.push('\n');
+        }
+    }
+    csv
+}
+
+/// Render the texts of video frames as subtitle cues. Every frame is given with the time (in
+/// seconds) it's shown from, until the next frame or `end` for the last one. Consecutive frames
+/// with the same text make one cue, and frames without text end the previous cue.
+pub fn render_subtitles(frames: &[(f64, &str)], end: f64, format: SubtitleFormat) -> String {
+    let mut cues: Vec<(f64, f64, String)> = Vec::new();
+    for (index, (start, text)) in frames.iter().enumerate() {
+        let stop = frames.get(index + 1).map_or(end, |(next, _)| *next);
+        let text = text.lines().map(str::trim).filter(|line| !line.is_empty()).collect::<Vec<_>>().join("\n");
+        match cues.last_mut() {
+            Some((_, cue_stop, cue_text)) if *cue_text == text && *cue_stop == *start => *cue_stop = stop,
+            _ if text.is_empty() => {}
+            _ => cues.push((*start, stop, text)),
+        }
+    }
+
+    let mut output = String::new();
+    if format == SubtitleFormat::Vtt {
+        output.push_str("WEBVTT\n\n");
+    }
+    for (index, (start, stop, text)) in cues.iter().enumerate() {
+        if format == SubtitleFormat::Srt {
+            output.push_str(&format!("{}\n", index + 1));
+        }
+        output.push_str(&format!("{} --> {}\n{}\n\n", cue_time(*start, format), cue_time(*stop, format), text));
+    }
+    output
+}
+
+// Format a time in seconds as HH:MM:SS,mmm (SRT) or HH:MM:SS.mmm (WebVTT)
+fn cue_time(seconds: f64, format: SubtitleFormat) -> String {
+    let millis = (seconds * 1000.0).round() as u64;
+    let separator = if format == SubtitleFormat::Srt { ',' } else { '.' };
+    format!("{:02}:{:02}:{:02}{}{:03}", millis / 3_600_000, millis / 60_000 % 60, millis / 1000 % 60, separator, millis % 1000)
+}
+
+/// Group the recognized lines by detected language ("und" when unknown)
+pub fn split_by_language(ocr_results: &[OCRResult]) -> BTreeMap<String, String> {
+    let mut texts: BTreeMap<String, String> = BTreeMap::new();
+    for item in ocr_results.iter().flat_map(|r| &r.boxes) {
+        let language = item.language.as_deref().unwrap_or("und");
+        let text = texts.entry(language.to_string()).or_default();
+        text.push_str(&item.text);
+        text.push('\n');
+    }
+    texts
+}
@@ -0,0 +1,63 @@
+use objc2_core_foundation::{CFData, CFRetained, CGPoint, CGRect, CGSize};
+use objc2_core_graphics::{CGContext, CGDataProvider, CGPDFBox, CGPDFDocument, CGPDFPage};
+use crate::bitmap::render_png;
+
+// resolution PDF pages are rendered at for OCR
+pub const RENDER_DPI: f64 = 200.0;
+// PDF user space unit (1 pt = 1/72 inch)
+const POINTS_PER_INCH: f64 = 72.0;
+
+pub fn is_pdf(bytes: &[u8]) -> bool {
+    infer::archive::is_pdf(bytes)
+}
+
+// Number of pages of a PDF
+pub fn page_count(bytes: &[u8]) -> Option<usize> {
+    let document = open_document(bytes)?;
+    Some(unsafe { CGPDFDocument::number_of_pages(Some(&document)) })
+}
+
+// Render every page of a PDF (or only the given 0-based page) to a PNG
+pub fn render_pages(bytes: &[u8], only_page: Option<usize>) -> Option<Vec<Vec<u8>>> {
+    let document = open_document(bytes)?;
+
+    let page_count = unsafe { CGPDFDocument::number_of_pages(Some(&document)) };
+    // PDF page numbers are 1-based
+    (1..=page_count)
+        .filter(|number| only_page.is_none_or(|page| page + 1 == *number))
+        .map(|number| {
+            let page = unsafe { CGPDFDocument::page(Some(&document), number) }?;
+            render_page(&page)
+        })
+        .collect()
+}
+
+fn open_document(bytes: &[u8]) -> Option<CFRetained<CGPDFDocument>> {
+    let data = CFData::from_bytes(bytes);
+    let provider = unsafe { CGDataProvider::with_cf_data(Some(&data)) }?;
+    unsafe { CGPDFDocument::with_provider(Some(&provider)) }
+}
+
+fn render_page(page: &CGPDFPage) -> Option<Vec<u8>> {
+    let media_box = unsafe { CGPDFPage::box_rect(Some(page), CGPDFBox::MediaBox) };
+    let rotated = unsafe { CGPDFPage::rotation_angle(Some(page)) } % 180 != 0;
+    let (page_width, page_height) = if rotated {
+        (media_box.size.height, media_box.size.width)
+    } else {
+        (media_box.size.width, media_box.size.height)
+    };
+
+    let scale = RENDER_DPI / POINTS_PER_INCH;
+    let width = (page_width * scale).round() as usize;
+    let height = (page_height * scale).round() as usize;
+
+    // The drawing transform applies the page rotation and maps the media box to the origin,
+    // but never scales up, so the DPI scaling is applied separately
+    let rect = CGRect::new(CGPoint::new(0.0, 0.0), CGSize::new(page_width, page_height));
+    render_png(width, height, |context| unsafe {
+        CGContext::scale_ctm(Some(context), scale, scale);
+        let transform = CGPDFPage::drawing_transform(Some(page), CGPDFBox::MediaBox, rect, 0, true);
+        CGContext::concat_ctm(Some(context), transform);
+        CGContext::draw_pdf_page(Some(context), Some(page));
+    })
+}
@@ -0,0 +1,1200 @@
+use tower_http::{cors::{AllowOrigin, CorsLayer}, trace::{DefaultOnFailure, DefaultOnRequest, DefaultOnResponse, TraceLayer}};
+use tracing::{field, Level, Span};
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+use std::{collections::HashMap, io::{self, Cursor, Write}, os::unix::fs::FileTypeExt, net::{IpAddr, SocketAddr}, path::{Path, PathBuf}, sync::{Arc, Mutex}, time::{Duration, Instant}};
+use image::{GenericImageView, ImageFormat, Rgba};
+use imageproc::{drawing::draw_hollow_rect_mut, rect::Rect};
+use axum::{
+    body::{Body, Bytes},
+    extract::{multipart::MultipartError, rejection::JsonRejection, ws::{Message, WebSocket, WebSocketUpgrade}, ConnectInfo, DefaultBodyLimit, Multipart, Query, Request, State}, 
+    http::{header, HeaderMap, HeaderName, HeaderValue, Method, StatusCode}, 
+    middleware::{self, Next}, 
+    response::{Html, IntoResponse, Response}, 
+    routing::{get, post}, 
+    Json, 
+    Router
+};
+use futures_util::{stream, SinkExt, StreamExt};
+use tokio::{signal::unix::{signal, SignalKind}, sync::{watch, Semaphore}};
+use base64::{Engine as _, engine::general_purpose};
+use termcolor::{Color, ColorChoice, ColorSpec, StandardStream, WriteColor};
+use uuid::Uuid;
+use serde::{Deserialize, Serialize};
+use clap::ValueEnum;
+use regex::Regex;
+use subtle::{Choice, ConstantTimeEq};
+use macocr::{check_image_type, detect_file_type, get_ocr_result_from_bytes, OCRBarcodeItem, OCRBoxItem, OCROptions, OCRResult, OcrError, RecognitionLevel, VERSION, warm_up};
+use macocr::output::render_svg;
+use crate::{metrics::Metrics, Args};
+
+// upload dir name
+const UPLOAD_DIR_NAME: &str = "macocr_uploads";
+
+// header carrying the id of a request
+const REQUEST_ID_HEADER: &str = "x-request-id";
+// longest request id accepted from a client, longer ones are replaced
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+// window of the --rate-limit request count
+const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(60);
+
+/// Format of the server logs
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum LogFormat {
+    /// Human-readable lines
+    Text,
+    /// One JSON object per line, for log aggregators
+    Json,
+}
+
+// Shared state of the HTTP server
+#[derive(Clone)]
+struct ServerState {
+    options: OCROptions,
+    preview_size: Option<u32>,
+    preview_boxes: bool,
+    metrics: Option<Arc<Metrics>>,
+    // where uploads are kept, with --keep-uploads or --upload-dir
+    upload_dir: Option<PathBuf>,
+    max_upload_size: u64,
+    // limits the OCR calls running at once with --max-concurrent-ocr
+    ocr_permits: Option<Arc<Semaphore>>,
+}
+
+impl ServerState {
+    // Status and message of a failure to read the multipart body
+    fn multipart_error(&self, e: &MultipartError) -> (StatusCode, String) {
+        match e.status() {
+            StatusCode::PAYLOAD_TOO_LARGE => (
+                StatusCode::PAYLOAD_TOO_LARGE,
+                format!("The upload exceeds the maximum size of {} MB", self.max_upload_size),
+            ),
+            status => (status, e.body_text()),
+        }
+    }
+
+    // Count an upload in the metrics if they're enabled: every endpoint that OCRs an image counts
+    // as an API upload, except /upload answering a browser with HTML
+    fn record_upload(&self, is_api_request: bool) {
+        if let Some(metrics) = &self.metrics {
+            metrics.record_upload(is_api_request);
+        }
+    }
+
+    // Run an OCR call on the blocking thread pool, so Vision doesn't block the async workers,
+    // recording it in the metrics if they're enabled. With --max-concurrent-ocr, the call waits
+    // for a permit first; the permit is held until the call returns, even if the request is dropped.
+    async fn ocr<T: Send + 'static>(&self, ocr: impl FnOnce() -> Result<T, OcrError> + Send + 'static) -> Result<T, OcrError> {
+        let permit = match &self.ocr_permits {
+            Some(permits) => Some(permits.clone().acquire_owned().await.expect("the OCR semaphore is never closed")),
+            None => None,
+        };
+        let metrics = self.metrics.clone();
+        let result = run_blocking(move || {
+            let _permit = permit;
+            match metrics {
+                Some(metrics) => metrics.observe_ocr(ocr),
+                None => ocr(),
+            }
+        }).await;
+        // The handlers run in the span of their request (see the TraceLayer)
+        Span::current().record("ocr_success", result.is_ok());
+        result
+    }
+}
+
+// Query parameters of the upload endpoint
+#[derive(Deserialize)]
+struct UploadQuery {
+    level: Option<RecognitionLevel>,
+    normalized: Option<bool>,
+}
+
+impl UploadQuery {
+    // The server's OCR options with the overrides of the query parameters
+    fn options(&self, defaults: &OCROptions) -> OCROptions {
+        let mut options = defaults.clone();
+        if let Some(level) = self.level {
+            options.level = level;
+        }
+        if let Some(normalized) = self.normalized {
+            options.normalized = normalized;
+        }
+        options
+    }
+}
+
+// JSON body of POST /ocr
+#[derive(Deserialize)]
+struct OcrRequest {
+    // base64 encoded image, optionally as a data URL
+    image: String,
+}
+
+// Query parameters of /upload/svg
+#[derive(Deserialize)]
+struct SvgQuery {
+    #[serde(default)]
+    labels: bool,
+}
+
+// Format of the upload responses, negotiated with the Accept header
+#[derive(Clone, Copy, PartialEq)]
+enum ResponseFormat {
+    Html,
+    Json,
+    // Only the recognized text, for command line clients like curl
+    Text,
+}
+
+impl ResponseFormat {
+    fn from_headers(headers: &HeaderMap) -> Self {
+        let accept = headers.get(header::ACCEPT).and_then(|v| v.to_str().ok()).unwrap_or_default();
+        if accept.contains("application/json") {
+            ResponseFormat::Json
+        } else if accept.contains("text/plain") {
+            ResponseFormat::Text
+        } else {
+            ResponseFormat::Html
+        }
+    }
+}
+
+// Upload Json Response
+#[derive(Serialize)]
+struct UploadResponse {
+    success: bool,
+    message: String,
+    ocr_result: String,
+    image_width: u32,
+    image_height: u32,
+    ocr_boxes: Vec<OCRBoxItem>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    barcodes: Vec<OCRBarcodeItem>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    document: Option<[[f64; 2]; 4]>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    detected_languages: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detected_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dpi: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    filename: Option<String>,
+    request_id: String
+}
+
+// Run the HTTP server
+pub async fn run_server(args: &Args, options: OCROptions) {
+    tracing_subscriber::registry()
+        .with(
+            tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| {
+                format!("{}=debug,tower_http=debug", env!("CARGO_CRATE_NAME")).into()
+            }),
+        )
+        .with((args.log_format == LogFormat::Text).then(tracing_subscriber::fmt::layer))
+        .with((args.log_format == LogFormat::Json).then(|| tracing_subscriber::fmt::layer().json()))
+        .init();
+
+    let mut stdout = StandardStream::stdout(ColorChoice::Always);
+
+    // Uploads are OCR'd in memory and only saved with --keep-uploads
+    let upload_dir = match &args.upload_dir {
+        Some(dir) => Some(dir.clone()),
+        None => args.keep_uploads.then(|| std::env::temp_dir().join(UPLOAD_DIR_NAME)),
+    };
+    if let Some(dir) = &upload_dir && let Err(e) = prepare_upload_dir(dir) {
+        eprintln!("Upload dir {} isn't writable: {}", dir.display(), e);
+        std::process::exit(1);
+    }
+
+    // The first OCR loads Vision's recognition model, so load it before serving the first upload
+    warm_up(&options);
+
+    let state = ServerState {
+        // The JSON responses include the detected languages
+        options: OCROptions { report_languages: true, ..options },
+        preview_size: args.preview_size,
+        preview_boxes: args.preview_boxes,
+        metrics: args.metrics.then(|| Arc::new(Metrics::new())),
+        upload_dir: upload_dir.clone(),
+        max_upload_size: args.max_upload_size,
+        ocr_permits: args.max_concurrent_ocr.map(|permits| Arc::new(Semaphore::new(permits.into()))),
+    };
+
+    let upload_routes = Router::new()
+    .route("/upload", post(upload_file))
+    .route("/upload/annotated", post(upload_annotated))
+    .route("/upload/svg", post(upload_svg))
+    .route("/upload/batch", post(upload_batch))
+    .route("/ocr", post(ocr_base64));
+
+    let upload_routes = if let Some(limit) = args.rate_limit {
+        print!("Rate limit: ");
+        stdout.set_color(ColorSpec::new().set_fg(Some(Color::Blue)).set_bold(true)).unwrap();
+        writeln!(&mut stdout, "{} requests/min", limit).unwrap();
+        stdout.reset().unwrap();
+
+        upload_routes.route_layer(middleware::from_fn_with_state(RateLimiter::new(limit), rate_limit_middleware))
+    } else {
+        upload_routes
+    };
+
+    let app = Router::new()
+    .route("/", get(show_form))
+    .route("/ws", get(ws_handler))
+    .merge(upload_routes);
+
+    let app = if args.metrics {
+        app.route("/metrics", get(show_metrics))
+    } else {
+        app
+    };
+
+    let app = app
+    .with_state(state)
+    // Enforced while reading the multipart body, so the handlers can answer with 413 in their own format
+    .layer(DefaultBodyLimit::max(args.max_upload_size as usize * 1024 * 1024))
+    .layer(
+        TraceLayer::new_for_http()
+            .make_span_with(request_span)
+            .on_request(
+                DefaultOnRequest::new()
+                    .level(Level::INFO)
+            )
+            .on_response(
+                DefaultOnResponse::new()
+                    .level(Level::INFO)
+                    .latency_unit(tower_http::LatencyUnit::Millis),
+            )
+            .on_failure(
+                DefaultOnFailure::new()
+                    .level(Level::ERROR)
+            )
+    );
+
+    let basic_auth = if !args.auth.is_empty() && is_valid_auth_format(&args.auth) {
+        print!("      Auth: ");
+        stdout.set_color(ColorSpec::new().set_fg(Some(Color::Blue)).set_bold(true)).unwrap();
+        writeln!(&mut stdout, "{}", args.auth).unwrap();
+        stdout.reset().unwrap();
+
+        let (username, password) = args.auth.split_once(':').unwrap();
+        Some((username.to_string(), password.to_string()))
+    } else {
+        None
+    };
+
+    if let Some(token) = &args.token {
+        print!("     Token: ");
+        stdout.set_color(ColorSpec::new().set_fg(Some(Color::Blue)).set_bold(true)).unwrap();
+        writeln!(&mut stdout, "{}", token).unwrap();
+        stdout.reset().unwrap();
+    }
+
+    let app = if basic_auth.is_some() || args.token.is_some() {
+        let credentials = Credentials {
+            basic: basic_auth,
+            token: args.token.clone(),
+        };
+
+        app.layer(middleware::from_fn(move |headers, request, next| {
+            auth_middleware_with_params(headers, request, next, credentials.clone())
+        }))
+    } else {
+        app
+    };
+
+    // Monitoring probes, merged after the auth layer so load balancers can reach them without credentials
+    let app = app
+    .route("/health", get(health))
+    .route("/version", get(version))
+    // Around the logs and auth, so every response carries the id logged for its request
+    .layer(middleware::from_fn(request_id_middleware));
+
+    // Added last so that CORS preflight requests are answered before the auth check
+    let app = if let Some(origins) = &args.cors_origin {
+        print!("      CORS: ");
+        stdout.set_color(ColorSpec::new().set_fg(Some(Color::Blue)).set_bold(true)).unwrap();
+        writeln!(&mut stdout, "{}", origins).unwrap();
+        stdout.reset().unwrap();
+
+        app.layer(cors_layer(origins))
+    } else {
+        app
+    };
+
+    let address = match &args.unix_socket {
+        Some(path) => format!("unix:{}", path),
+        None => format!("http://{}", SocketAddr::new(args.host, args.port)),
+    };
+    if args.unix_socket.is_none() && args.host.is_unspecified() {
+        eprintln!("Warning: the server listens on all network interfaces; use --host 127.0.0.1 to only accept local connections");
+    }
+
+    print!("   Address: ");
+    stdout.set_color(ColorSpec::new().set_fg(Some(Color::Blue)).set_bold(true)).unwrap();
+    writeln!(&mut stdout, "{}", address).unwrap();
+    stdout.reset().unwrap();
+
+    if let Some(dir) = &upload_dir {
+        print!("Upload dir: ");
+        stdout.set_color(ColorSpec::new().set_fg(Some(Color::Blue)).set_bold(true)).unwrap();
+        writeln!(&mut stdout, "{}", dir.display()).unwrap();
+        stdout.reset().unwrap();
+    }
+    println!("");
+
+    if let Some(path) = &args.unix_socket {
+        // A socket left behind by a previous run would make the bind fail
+        if std::fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+            std::fs::remove_file(path).unwrap();
+        }
+        let listener = tokio::net::UnixListener::bind(path).unwrap();
+        axum::serve(listener, app.into_make_service())
+            .with_graceful_shutdown(shutdown_signal())
+            .await
+            .unwrap();
+        let _ = std::fs::remove_file(path);
+    } else {
+        let listener = tokio::net::TcpListener::bind(SocketAddr::new(args.host, args.port)).await.unwrap();
+        axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
+            .with_graceful_shutdown(shutdown_signal())
+            .await
+            .unwrap();
+    }
+
+    println!("Server stopped");
+}
+
+// Resolve on SIGINT (Ctrl+C) or SIGTERM, letting in-flight requests finish
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c().await.expect("failed to install the Ctrl+C handler");
+    };
+    let terminate = async {
+        signal(SignalKind::terminate())
+            .expect("failed to install the SIGTERM handler")
+            .recv()
+            .await;
+    };
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+    println!("Shutting down, waiting for in-flight requests to finish...");
+}
+
+// Fixed-window request counter per client IP
+#[derive(Clone)]
+struct RateLimiter {
+    limit: u32,
+    windows: Arc<Mutex<HashMap<IpAddr, (Instant, u32)>>>,
+}
+
+impl RateLimiter {
+    fn new(limit: u32) -> Self {
+        RateLimiter {
+            limit,
+            windows: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    // Count a request of the client, or return the time until its window resets if it's over the limit
+    fn check(&self, ip: IpAddr) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut windows = self.windows.lock().unwrap();
+        windows.retain(|_, (start, _)| now.duration_since(*start) < RATE_LIMIT_WINDOW);
+
+        let (start, count) = windows.entry(ip).or_insert((now, 0));
+        if *count >= self.limit {
+            return Err(RATE_LIMIT_WINDOW - now.duration_since(*start));
+        }
+        *count += 1;
+        Ok(())
+    }
+}
+
+// Reject requests over the client's rate limit with 429 Too Many Requests
+async fn rate_limit_middleware(
+    State(limiter): State<RateLimiter>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    request: Request,
+    next: Next,
+) -> Response {
+    match limiter.check(addr.ip()) {
+        Ok(()) => next.run(request).await,
+        Err(retry_after) => (
+            StatusCode::TOO_MANY_REQUESTS,
+            [(header::RETRY_AFTER, retry_after.as_secs_f64().ceil().to_string())],
+            "Too many requests",
+        ).into_response(),
+    }
+}
+
+// CORS layer allowing any origin for "*", otherwise the comma-separated origins
+fn cors_layer(origins: &str) -> CorsLayer {
+    let allow_origin = if origins == "*" {
+        AllowOrigin::any()
+    } else {
+        AllowOrigin::list(origins.split(',').map(|origin| HeaderValue::from_str(origin.trim()).unwrap()))
+    };
+    CorsLayer::new()
+        .allow_origin(allow_origin)
+        .allow_methods([Method::GET, Method::POST])
+        .allow_headers([header::ACCEPT, header::AUTHORIZATION, header::CONTENT_TYPE, HeaderName::from_static(REQUEST_ID_HEADER)])
+        .expose_headers([HeaderName::from_static(REQUEST_ID_HEADER)])
+}
+
+// Health check probe
+async fn health() -> Json<serde_json::Value> {
+    Json(serde_json::json!({ "status": "ok" }))
+}
+
+// Version of the server
+async fn version() -> Json<serde_json::Value> {
+    Json(serde_json::json!({ "version": VERSION }))
+}
+
+// Prometheus metrics of the server
+async fn show_metrics(State(state): State<ServerState>) -> Response {
+    match &state.metrics {
+        Some(metrics) => (
+            [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+            metrics.render(),
+        ).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+// Show file upload form
+async fn show_form() -> Html<String> {
+    let html = format!(
+        r#"
+        <!doctype html>
+        <html>
+        <head>
+            <meta charset="utf-8">
+            <meta name="viewport" content="width=device-width, initial-scale=1.0">
+            <title>macocr</title>
+        </head>
+        <body>
+            <h1>macocr v{}</h1>
+            <form action="/upload" method="post" enctype="multipart/form-data">
+                <label>
+                    Choose file: 
+                    <input type="file" name="file" required>
+                </label>
+                <br><br>
+                <input type="submit" value="Upload file">
+            </form>
+        </body>
+        </html>
+        "#, 
+        VERSION
+    );
+    Html(html)
+}
+
+// Handle single file upload – supports HTML and JSON responses
+async fn upload_file(
+    State(state): State<ServerState>,
+    Query(query): Query<UploadQuery>,
+    headers: HeaderMap,
+    mut multipart: Multipart
+) -> Response {
+    let mut options = query.options(&state.options);
+    let response_format = ResponseFormat::from_headers(&headers);
+    // The preview is drawn from the image that was OCR'd, e.g. with --input-rotate applied, and
+    // only the HTML response has one, so JSON responses don't keep a copy of the image
+    options.keep_image |= state.preview_size.is_some() && response_format == ResponseFormat::Html;
+    options.report_languages = response_format == ResponseFormat::Json;
+
+    let request_id = request_id(&headers);
+    state.record_upload(response_format != ResponseFormat::Html);
+    
+    // Get the first field
+    let field = match multipart.next_field().await {
+        Ok(Some(field)) => field,
+        Ok(None) => return upload_error_response(response_format, request_id, StatusCode::BAD_REQUEST, "No file received"),
+        Err(e) => {
+            let (status, message) = state.multipart_error(&e);
+            return upload_error_response(response_format, request_id, status, &message);
+        }
+    };
+    let original_name = field.file_name().unwrap_or("unnamed").to_string();
+    let data = match field.bytes().await {
+        Ok(data) => data,
+        Err(e) => {
+            let (status, message) = state.multipart_error(&e);
+            return upload_error_response(response_format, request_id, status, &message);
+        }
+    };
+    
+    // With --keep-uploads, keep a copy of the upload; OCR works on the bytes in memory
+    if let Some(dir) = &state.upload_dir && let Err(message) = save_upload(dir, &original_name, &data) {
+        return upload_error_response(response_format, request_id, StatusCode::INTERNAL_SERVER_ERROR, message);
+    }
+
+    let mut success = false;
+    let title;
+    let message;
+    let mut ocr_result_text= "".to_string();
+    let mut image_width = 0;
+    let mut image_height = 0;
+    let mut ocr_boxes = Vec::new();
+    let mut barcodes = Vec::new();
+    let mut document = None;
+    let mut detected_languages = Vec::new();
+    let mut dpi = None;
+    let mut preview = String::new();
+
+    let mut status = StatusCode::OK;
+
+    let detected_type = detect_file_type(&data).map(String::from);
+    let result = state.ocr({
+        let (data, options) = (data.clone(), options.clone());
+        move || {
+            check_image_type(&data)?;
+            get_ocr_result_from_bytes(&data, &options)
+        }
+    }).await;
+    match result {
+        Ok(mut ocr_result) => {
+            if let Some(preview_size) = state.preview_size {
+                let (preview_boxes, normalized) = (state.preview_boxes, options.normalized);
+                (preview, ocr_result) = run_blocking(move || {
+                    (preview_html(preview_size, &ocr_result, preview_boxes, normalized), ocr_result)
+                }).await;
+            }
+            ocr_result_text = ocr_result.text;
+            image_width = ocr_result.image_width;
+            image_height = ocr_result.image_height;
+            ocr_boxes = ocr_result.boxes;
+            barcodes = ocr_result.barcodes;
+            document = ocr_result.document;
+            detected_languages = ocr_result.detected_languages;
+            dpi = ocr_result.dpi;
+            message = "File uploaded successfully".to_string();
+            title = "OCR Result:".to_string();
+            success = true;
+        }
+        Err(e) => {
+            status = ocr_error_status(&e);
+            title = format!("❌ {}", e);
+            message = e.to_string();
+        }
+    }
+
+    if response_format == ResponseFormat::Text {
+        let text = if success { ocr_result_text } else { format!("{}\n", message) };
+        (status, text).into_response()
+    } else if response_format == ResponseFormat::Json {
+        (status, Json(UploadResponse {
+            success: success,
+            message: message.to_string(),
+            ocr_result: ocr_result_text,
+            image_width: image_width,
+            image_height: image_height,
+            ocr_boxes: ocr_boxes,
+            barcodes,
+            document,
+            detected_languages,
+            detected_type,
+            dpi,
+            filename: Some(original_name),
+            request_id
+        })).into_response()
+    } else {
+        (status, Html(format!(
+            r#"
+            <!doctype html>
+            <html>
+            <head>
+                <meta charset="utf-8">
+                <meta name="viewport" content="width=device-width, initial-scale=1.0">
+                <title>OCR Result</title>
+            </head>
+            <body>
+                <h1>{}</h1>
+                {}
+                <pre>{}</pre>
+            </body>
+            </html>
+            "#,
+            title, preview, ocr_result_text.replace("&", "&amp;").replace("<", "&lt;").replace(">", "&gt;")
+        ))).into_response()
+    }
+}
+
+// HTTP status of an OCR failure, shared by the upload endpoints
+fn ocr_error_status(e: &OcrError) -> StatusCode {
+    match e {
+        OcrError::NotAnImage | OcrError::UnsupportedImageType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+        // Recognition failures are server-side errors
+        OcrError::Io(_) | OcrError::VisionFailed(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        // The file claims to be an image but its contents are broken
+        OcrError::DecodeFailed | OcrError::FrameOutOfRange { .. } => StatusCode::BAD_REQUEST,
+        OcrError::ImageTooSmall(..) => StatusCode::UNPROCESSABLE_ENTITY,
+    }
+}
+
+// Error response of the upload endpoint, in the negotiated format
+fn upload_error_response(response_format: ResponseFormat, request_id: String, status: StatusCode, message: &str) -> Response {
+    if response_format == ResponseFormat::Text {
+        (status, format!("{}\n", message)).into_response()
+    } else if response_format == ResponseFormat::Json {
+        (status, Json(UploadResponse {
+            success: false,
+            message: message.to_string(),
+            ocr_result: "".to_string(),
+            image_width: 0,
+            image_height: 0,
+            ocr_boxes: Vec::new(),
+            barcodes: Vec::new(),
+            document: None,
+            detected_languages: Vec::new(),
+            detected_type: None,
+            dpi: None,
+            filename: None,
+            request_id
+        })).into_response()
+    } else {
+        (status, Html(format!(
+            r#"
+            <!doctype html>
+            <head>
+                <meta charset="utf-8">
+                <meta name="viewport" content="width=device-width, initial-scale=1.0">
+                <title>Error</title>
+            </head>
+            <html><body>
+                <h1>❌ {}</h1>
+            </body></html>
+            "#,
+            message.replace("&", "&amp;").replace("<", "&lt;").replace(">", "&gt;")
+        ))).into_response()
+    }
+}
+
+// Create the upload dir and check that files can be written in it, so a read-only or missing
+// volume is reported at startup instead of on every upload
+fn prepare_upload_dir(dir: &Path) -> io::Result<()> {
+    std::fs::create_dir_all(dir)?;
+    let probe = dir.join(format!(".{}", Uuid::new_v4()));
+    std::fs::write(&probe, b"")?;
+    std::fs::remove_file(&probe)
+}
+
+// Save an upload to the upload dir under a random name, preserving the original file extension
+fn save_upload(dir: &Path, original_name: &str, data: &[u8]) -> Result<(), &'static str> {
+    let file_extension = std::path::Path::new(original_name)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or("");
+
+    let random_name = if file_extension.is_empty() {
+        Uuid::new_v4().to_string()
+    } else {
+        format!("{}.{}", Uuid::new_v4(), file_extension)
+    };
+
+    let save_path = dir.join(random_name);
+    let mut file = std::fs::File::create(&save_path).map_err(|_| "Unable to create file")?;
+    file.write_all(data).map_err(|_| "Failed to write file")
+}
+
+// Handle multiple file upload – responds with a JSON array of results, one per file field,
+// or streams them as NDJSON lines as soon as each file is done for `Accept: application/x-ndjson`
+async fn upload_batch(
+    State(state): State<ServerState>,
+    Query(query): Query<UploadQuery>,
+    headers: HeaderMap,
+    mut multipart: Multipart
+) -> Response {
+    let options = query.options(&state.options);
+    let request_id = request_id(&headers);
+    state.record_upload(true);
+
+    let is_ndjson_request = headers.get("accept")
+        .and_then(|v| v.to_str().ok())
+        .map(|accept| accept.contains("application/x-ndjson"))
+        .unwrap_or(false);
+
+    if is_ndjson_request {
+        let lines = stream::unfold(Some(multipart), move |multipart| {
+            let state = state.clone();
+            let options = options.clone();
+            let request_id = request_id.clone();
+            async move {
+                // A multipart error ends the stream after reporting it
+                let mut multipart = multipart?;
+                match next_batch_response(&mut multipart, &state, &options, &request_id).await {
+                    Ok(Some(response)) => {
+                        let mut line = serde_json::to_vec(&response).unwrap();
+                        line.push(b'\n');
+                        Some((Ok(line), Some(multipart)))
+                    }
+                    Ok(None) => None,
+                    Err(e) => Some((Err(io::Error::other(e)), None)),
+                }
+            }
+        });
+        return ([(header::CONTENT_TYPE, "application/x-ndjson")], Body::from_stream(lines)).into_response();
+    }
+
+    let mut responses = Vec::new();
+    loop {
+        match next_batch_response(&mut multipart, &state, &options, &request_id).await {
+            Ok(Some(response)) => responses.push(response),
+            Ok(None) => break,
+            Err(e) => return state.multipart_error(&e).into_response(),
+        }
+    }
+
+    if responses.is_empty() {
+        return (StatusCode::BAD_REQUEST, "No file received").into_response();
+    }
+    Json(responses).into_response()
+}
+
+// OCR the next file field of a batch upload, or None after the last one
+async fn next_batch_response(
+    multipart: &mut Multipart,
+    state: &ServerState,
+    options: &OCROptions,
+    request_id: &str
+) -> Result<Option<UploadResponse>, MultipartError> {
+    let Some(field) = multipart.next_field().await? else {
+        return Ok(None);
+    };
+    let filename = field.file_name().unwrap_or("unnamed").to_string();
+    let data = field.bytes().await?;
+
+    let detected_type = detect_file_type(&data).map(String::from);
+    let options = options.clone();
+    let result = state.ocr(move || {
+        check_image_type(&data)?;
+        get_ocr_result_from_bytes(&data, &options)
+    }).await;
+    Ok(Some(match result {
+        Ok(ocr_result) => UploadResponse {
+            success: true,
+            message: "File uploaded successfully".to_string(),
+            ocr_result: ocr_result.text,
+            image_width: ocr_result.image_width,
+            image_height: ocr_result.image_height,
+            ocr_boxes: ocr_result.boxes,
+            barcodes: ocr_result.barcodes,
+            document: ocr_result.document,
+            detected_languages: ocr_result.detected_languages,
+            detected_type,
+            dpi: ocr_result.dpi,
+            filename: Some(filename),
+            request_id: request_id.to_string()
+        },
+        Err(e) => UploadResponse {
+            success: false,
+            message: e.to_string(),
+            ocr_result: "".to_string(),
+            image_width: 0,
+            image_height: 0,
+            ocr_boxes: Vec::new(),
+            barcodes: Vec::new(),
+            document: None,
+            detected_languages: Vec::new(),
+            detected_type,
+            dpi: None,
+            filename: Some(filename),
+            request_id: request_id.to_string()
+        },
+    }))
+}
+
+// Handle single file upload – responds with the image (PNG) with the detected boxes drawn in red
+async fn upload_annotated(
+    State(state): State<ServerState>,
+    Query(query): Query<UploadQuery>,
+    mut multipart: Multipart
+) -> Response {
+    let mut options = query.options(&state.options);
+    // Draw on the image the boxes refer to (e.g. after --input-rotate), in pixels
+    options.keep_image = true;
+    options.normalized = false;
+    options.report_languages = false;
+    state.record_upload(true);
+
+    let field = match multipart.next_field().await {
+        Ok(Some(field)) => field,
+        Ok(None) => return (StatusCode::BAD_REQUEST, "No file received").into_response(),
+        Err(e) => return state.multipart_error(&e).into_response(),
+    };
+    let data = match field.bytes().await {
+        Ok(data) => data,
+        Err(e) => return state.multipart_error(&e).into_response(),
+    };
+    if let Err(e) = check_image_type(&data) {
+        return (StatusCode::UNSUPPORTED_MEDIA_TYPE, e.to_string()).into_response();
+    }
+
+    let ocr_result = match state.ocr(move || get_ocr_result_from_bytes(&data, &options)).await {
+        Ok(ocr_result) => ocr_result,
+        Err(e) => return (ocr_error_status(&e), e.to_string()).into_response(),
+    };
+
+    match run_blocking(move || annotate_image(&ocr_result)).await {
+        Some(png) => ([(header::CONTENT_TYPE, "image/png")], png).into_response(),
+        None => (StatusCode::INTERNAL_SERVER_ERROR, "Failed to draw the annotated image").into_response(),
+    }
+}
+
+// Handle a base64 encoded image in a JSON body – responds with the OCR result as JSON
+async fn ocr_base64(
+    State(state): State<ServerState>,
+    Query(query): Query<UploadQuery>,
+    payload: Result<Json<OcrRequest>, JsonRejection>
+) -> Response {
+    let options = query.options(&state.options);
+    state.record_upload(true);
+
+    let error = |status: StatusCode, message: String| (status, Json(serde_json::json!({ "error": message }))).into_response();
+    let request = match payload {
+        Ok(Json(request)) => request,
+        Err(e) => return error(e.status(), e.body_text()),
+    };
+    // Data URLs (data:image/png;base64,...) are accepted as they are, e.g. from a canvas
+    let encoded = match request.image.split_once(";base64,") {
+        Some((prefix, encoded)) if prefix.starts_with("data:") => encoded,
+        _ => request.image.as_str(),
+    };
+    let data = match general_purpose::STANDARD.decode(encoded.trim()) {
+        Ok(data) => data,
+        Err(e) => return error(StatusCode::BAD_REQUEST, format!("Invalid base64 image: {}", e)),
+    };
+
+    let result = state.ocr(move || {
+        check_image_type(&data)?;
+        get_ocr_result_from_bytes(&data, &options)
+    }).await;
+    match result {
+        Ok(ocr_result) => Json(ocr_result).into_response(),
+        Err(e) => error(ocr_error_status(&e), e.to_string()),
+    }
+}
+
+// Handle single file upload – responds with an SVG overlay of the detected boxes
+async fn upload_svg(
+    State(state): State<ServerState>,
+    Query(query): Query<UploadQuery>,
+    Query(svg_query): Query<SvgQuery>,
+    mut multipart: Multipart
+) -> Response {
+    let mut options = query.options(&state.options);
+    // The overlay is sized to the image in pixels
+    options.normalized = false;
+    options.report_languages = false;
+    state.record_upload(true);
+
+    let field = match multipart.next_field().await {
+        Ok(Some(field)) => field,
+        Ok(None) => return (StatusCode::BAD_REQUEST, "No file received").into_response(),
+        Err(e) => return state.multipart_error(&e).into_response(),
+    };
+    let data = match field.bytes().await {
+        Ok(data) => data,
+        Err(e) => return state.multipart_error(&e).into_response(),
+    };
+    if let Err(e) = check_image_type(&data) {
+        return (StatusCode::UNSUPPORTED_MEDIA_TYPE, e.to_string()).into_response();
+    }
+
+    match state.ocr(move || get_ocr_result_from_bytes(&data, &options)).await {
+        Ok(ocr_result) => ([(header::CONTENT_TYPE, "image/svg+xml")], render_svg(&ocr_result, svg_query.labels)).into_response(),
+        Err(e) => (ocr_error_status(&e), e.to_string()).into_response(),
+    }
+}
+
+// Upgrade to a WebSocket that OCRs every binary image frame and replies with the OCR result as JSON
+async fn ws_handler(
+    State(state): State<ServerState>,
+    Query(query): Query<UploadQuery>,
+    ws: WebSocketUpgrade
+) -> Response {
+    let options = query.options(&state.options);
+    ws.on_upgrade(move |socket| handle_socket(socket, state, options))
+}
+
+// Receive frames while the latest one is OCR'd: a frame that arrives before the previous one was
+// picked up replaces it, so frames are dropped instead of queued when OCR can't keep up
+async fn handle_socket(socket: WebSocket, state: ServerState, options: OCROptions) {
+    let (mut sender, mut receiver) = socket.split();
+    let (frame_tx, mut frame_rx) = watch::channel::<Option<Bytes>>(None);
+
+    let receive_task = tokio::spawn(async move {
+        while let Some(Ok(message)) = receiver.next().await {
+            match message {
+                Message::Binary(frame) => {
+                    frame_tx.send_replace(Some(frame));
+                }
+                Message::Close(_) => break,
+                _ => {}
+            }
+        }
+    });
+
+    while frame_rx.changed().await.is_ok() {
+        let Some(frame) = frame_rx.borrow_and_update().clone() else {
+            continue;
+        };
+        // Every OCR'd frame counts as an upload, dropped frames don't
+        state.record_upload(true);
+        let options = options.clone();
+        let result = state.ocr(move || {
+            check_image_type(&frame)?;
+            get_ocr_result_from_bytes(&frame, &options)
+        }).await;
+
+        let json = match result {
+            Ok(ocr_result) => serde_json::to_string(&ocr_result).unwrap(),
+            Err(e) => serde_json::json!({ "error": e.to_string() }).to_string(),
+        };
+        if sender.send(Message::Text(json.into())).await.is_err() {
+            break;
+        }
+    }
+
+    receive_task.abort();
+}
+
+// Reuse the X-Request-Id sent by the client, or give the request a new one, for the handlers and
+// the logs, and echo it in the response
+async fn request_id_middleware(mut request: Request, next: Next) -> Response {
+    let request_id = request.headers()
+        .get(REQUEST_ID_HEADER)
+        .filter(|value| !value.is_empty() && value.len() <= MAX_REQUEST_ID_LENGTH && value.to_str().is_ok())
+        .cloned()
+        .unwrap_or_else(|| HeaderValue::from_str(&Uuid::new_v4().to_string()).unwrap());
+    request.headers_mut().insert(REQUEST_ID_HEADER, request_id.clone());
+
+    let mut response = next.run(request).await;
+    response.headers_mut().insert(REQUEST_ID_HEADER, request_id);
+    response
+}
+
+// Id of a request, set by request_id_middleware
+fn request_id(headers: &HeaderMap) -> String {
+    headers.get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+        .to_string()
+}
+
+// Span of a request, with its id and whether its OCR succeeded, recorded by the handlers that
+// OCR. The response is logged in it with its latency.
+fn request_span(request: &Request) -> Span {
+    tracing::info_span!(
+        "request",
+        method = %request.method(),
+        uri = %request.uri(),
+        request_id = %request_id(request.headers()),
+        ocr_success = field::Empty,
+    )
+}
+
+// Run blocking work (OCR, image decoding and encoding) on the blocking thread pool, so it doesn't
+// hold up the async workers, resuming its panic if it panics
+async fn run_blocking<T: Send + 'static>(work: impl FnOnce() -> T + Send + 'static) -> T {
+    tokio::task::spawn_blocking(work).await
+        .unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()))
+}
+
+// Draw the boxes of an OCR result as 2 px red rectangles on its image and encode it as PNG
+fn annotate_image(ocr_result: &OCRResult) -> Option<Vec<u8>> {
+    let mut img = image::load_from_memory(ocr_result.image.as_deref()?).ok()?.to_rgba8();
+    let red = Rgba([255, 0, 0, 255]);
+    for item in &ocr_result.boxes {
+        for inset in 0..2 {
+            let w = item.w.round() as i64 - 2 * inset;
+            let h = item.h.round() as i64 - 2 * inset;
+            if w > 0 && h > 0 {
+                let rect = Rect::at(item.x.round() as i32 + inset as i32, item.y.round() as i32 + inset as i32)
+                    .of_size(w as u32, h as u32);
+                draw_hollow_rect_mut(&mut img, rect, red);
+            }
+        }
+    }
+
+    let mut png = Vec::new();
+    img.write_to(&mut Cursor::new(&mut png), ImageFormat::Png).ok()?;
+    Some(png)
+}
+
+// Downscaled base64 thumbnail of the uploaded image, optionally with the boxes drawn on top
+fn preview_html(max_size: u32, ocr_result: &OCRResult, draw_boxes: bool, normalized: bool) -> String {
+    // The kept image is the one the boxes refer to (rotated, or the flattened document)
+    let Some(img) = ocr_result.image.as_deref().and_then(|image| image::load_from_memory(image).ok()) else {
+        return String::new();
+    };
+    // Normalized boxes are drawn in a unit view box, which is stretched to the thumbnail
+    let (width, height) = if normalized { (1, 1) } else { img.dimensions() };
+    let thumbnail = img.thumbnail(max_size, max_size);
+
+    let mut png = Vec::new();
+    if thumbnail.write_to(&mut Cursor::new(&mut png), ImageFormat::Png).is_err() {
+        return String::new();
+    }
+
+    // The overlay uses the original image coordinates and is scaled down with the thumbnail
+    let boxes: String = if draw_boxes {
+        ocr_result.boxes.iter()
+            .map(|item| format!(
+                r#"<rect x="{}" y="{}" width="{}" height="{}" fill="none" stroke="red" stroke-width="2" vector-effect="non-scaling-stroke"/>"#,
+                item.x, item.y, item.w, item.h
+            ))
+            .collect()
+    } else {
+        String::new()
+    };
+
+    format!(
+        r#"<div style="position: relative; display: inline-block;">
+            <img src="data:image/png;base64,{}" width="{}" height="{}" alt="preview">
+            <svg viewBox="0 0 {} {}" preserveAspectRatio="none" width="{}" height="{}" style="position: absolute; top: 0; left: 0;">{}</svg>
+        </div>"#,
+        general_purpose::STANDARD.encode(&png), thumbnail.width(), thumbnail.height(),
+        width, height, thumbnail.width(), thumbnail.height(), boxes
+    )
+}
+
+fn is_valid_auth_format(input: &str) -> bool {
+    let re = Regex::new(r"^[^:]+:[^:]+$").unwrap();
+    re.is_match(input)
+}
+
+// Accepted credentials of the auth middleware
+#[derive(Clone)]
+struct Credentials {
+    basic: Option<(String, String)>,
+    token: Option<String>,
+}
+
+// Check the Basic Auth username and password of a request
+fn is_valid_basic_auth(headers: &HeaderMap, username: &str, password: &str) -> bool {
+    if let Some(auth_header) = headers.get("authorization") {
+        if let Ok(auth_str) = auth_header.to_str() {
+            if auth_str.starts_with("Basic ") {
+                let encoded = &auth_str[6..]; // Remove the 'Basic ' prefix
+                if let Ok(decoded_bytes) = general_purpose::STANDARD.decode(encoded) {
+                    // Split the username and password, comparing bytes so non-UTF-8 credentials just fail
+                    if let Some(colon) = decoded_bytes.iter().position(|&b| b == b':') {
+                        let (user, pass) = (&decoded_bytes[..colon], &decoded_bytes[colon + 1..]);
+                        // Evaluate both comparisons so the timing doesn't tell which one failed
+                        let user_ok = digest_eq(user, username.as_bytes());
+                        let pass_ok = digest_eq(pass, password.as_bytes());
+                        return (user_ok & pass_ok).into();
+                    }
+                }
+            }
+        }
+    }
+    false
+}
+
+// Check the Bearer token of a request
+fn is_valid_bearer_token(headers: &HeaderMap, token: &str) -> bool {
+    headers.get("authorization")
+        .and_then(|v| v.as_bytes().strip_prefix(b"Bearer "))
+        .is_some_and(|bearer| digest_eq(bearer, token.as_bytes()).into())
+}
+
+// Compare secrets in constant time by their BLAKE3 digests, which all have the same length, since
+// comparing the slices themselves returns early on a length mismatch and leaks the secret's length
+fn digest_eq(a: &[u8], b: &[u8]) -> Choice {
+    blake3::hash(a).as_bytes().ct_eq(blake3::hash(b).as_bytes())
+}
+
+// Basic Auth and Bearer token middleware
+async fn auth_middleware_with_params(
+    headers: HeaderMap,
+    request: Request,
+    next: Next,
+    credentials: Credentials,
+) -> Result<Response, StatusCode> {
+    let basic_ok = credentials.basic.as_ref()
+        .is_some_and(|(username, password)| is_valid_basic_auth(&headers, username, password));
+    let token_ok = credentials.token.as_ref()
+        .is_some_and(|token| is_valid_bearer_token(&headers, token));
+    if basic_ok || token_ok {
+        // Authentication successful, proceeding with the request
+        return Ok(next.run(request).await);
+    }
+
+    // Authentication failed, return 401 and request authentication
+    let message = if credentials.basic.is_some() {
+        "Authentication failed: A valid username and password are required."
+    } else {
+        "Authentication failed: A valid bearer token is required."
+    };
+    let mut response = Response::new(message.into());
+    *response.status_mut() = StatusCode::UNAUTHORIZED;
+    if credentials.basic.is_some() {
+        response.headers_mut().append(
+            "WWW-Authenticate",
+            "Basic realm=\"MacOCR Server\"".parse().unwrap(),
+        );
+    }
+    if credentials.token.is_some() {
+        response.headers_mut().append(
+            "WWW-Authenticate",
+            "Bearer realm=\"MacOCR Server\"".parse().unwrap(),
+        );
+    }
+    
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // time a stand-in for a Vision call blocks its thread
+    const OCR_TIME: Duration = Duration::from_millis(200);
+
+    fn test_state(ocr_permits: Option<usize>) -> ServerState {
+        ServerState {
+            options: OCROptions::default(),
+            preview_size: None,
+            preview_boxes: false,
+            metrics: None,
+            upload_dir: None,
+            max_upload_size: 100,
+            ocr_permits: ocr_permits.map(|permits| Arc::new(Semaphore::new(permits))),
+        }
+    }
+
+    fn blocking_ocr() -> Result<(), OcrError> {
+        std::thread::sleep(OCR_TIME);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn simultaneous_ocr_calls_overlap() {
+        let state = test_state(None);
+        let start = Instant::now();
+        let (first, second) = tokio::join!(state.ocr(blocking_ocr), state.ocr(blocking_ocr));
+        first.unwrap();
+        second.unwrap();
+        assert!(start.elapsed() < OCR_TIME * 2, "the OCR calls ran one after another: {:?}", start.elapsed());
+    }
+
+    #[tokio::test]
+    async fn max_concurrent_ocr_queues_calls() {
+        let state = test_state(Some(1));
+        let start = Instant::now();
+        let (first, second) = tokio::join!(state.ocr(blocking_ocr), state.ocr(blocking_ocr));
+        first.unwrap();
+        second.unwrap();
+        assert!(start.elapsed() >= OCR_TIME * 2, "the OCR calls overlapped: {:?}", start.elapsed());
+    }
+}
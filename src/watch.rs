@@ -0,0 +1,115 @@
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+use std::{collections::{HashMap, HashSet}, fs, path::{Path, PathBuf}, sync::mpsc, thread, time::{Duration, Instant}};
+use macocr::{is_ocr_input, OCROptions, OCRResult};
+use macocr::output::{render_output, OutputFormat};
+use crate::{cache::Cache, export_results, get_ocr_results, report_success, write_output, Args, InputFile};
+
+// quiet period after the last event of a file before it's OCR'd
+const DEBOUNCE: Duration = Duration::from_millis(500);
+// attempts to read a file that isn't a complete image yet
+const IMAGE_RETRIES: u32 = 5;
+// extensions (lowercase) of the files that are OCR'd, any other file is ignored without reading it
+const INPUT_EXTENSIONS: [&str; 18] = [
+    "jpg", "jpeg", "png", "gif", "webp", "tif", "tiff", "bmp", "heic", "heif", "avif", "jxl", "jp2", "jpx", "cr2", "psd", "ico", "pdf"
+];
+
+// Watch a directory and OCR every image created or modified in it until interrupted
+pub fn run_watch(dir: &str, args: &Args, options: &OCROptions, cache: Option<&Cache>, strip_stdout: bool) -> notify::Result<()> {
+    let (tx, rx) = mpsc::channel::<notify::Result<Event>>();
+    let mut watcher = notify::recommended_watcher(tx)?;
+    let mode = if args.recursive { RecursiveMode::Recursive } else { RecursiveMode::NonRecursive };
+    // Event paths are absolute, so resolve the directory to export paths relative to it
+    let root = fs::canonicalize(dir)?;
+    watcher.watch(&root, mode)?;
+    eprintln!("Watching {} for new images...", dir);
+
+    // Files with pending events and the time of their latest event
+    let mut pending: HashMap<PathBuf, Instant> = HashMap::new();
+    // Files exported into the watched directory, which aren't OCR'd again (e.g. PDFs with --format pdf)
+    let mut exported: HashSet<PathBuf> = HashSet::new();
+    loop {
+        match rx.recv_timeout(DEBOUNCE) {
+            Ok(Ok(event)) => {
+                if matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) {
+                    for path in event.paths {
+                        pending.insert(path, Instant::now());
+                    }
+                }
+            }
+            Ok(Err(e)) => eprintln!("Watch error: {}", e),
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Err(mpsc::RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+
+        let settled: Vec<PathBuf> = pending.iter()
+            .filter(|(_, last_event)| last_event.elapsed() >= DEBOUNCE)
+            .map(|(path, _)| path.clone())
+            .collect();
+        for path in settled {
+            pending.remove(&path);
+            // Skip exported and non-image files (e.g. text exports) before waiting for them to settle
+            if exported.contains(&path) || !has_input_extension(&path) {
+                continue;
+            }
+            if let Some(file) = path.to_str() && path.is_file() && wait_for_image(file) {
+                process_image(file, &root, args, options, cache, strip_stdout, &mut exported);
+            }
+        }
+    }
+}
+
+fn has_input_extension(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| INPUT_EXTENSIONS.contains(&extension.to_ascii_lowercase().as_str()))
+}
+
+// Retry while a file that doesn't read as an image yet is still being written (empty or growing)
+fn wait_for_image(file: &str) -> bool {
+    let mut last_len = None;
+    for _ in 0..IMAGE_RETRIES {
+        if is_ocr_input(file) {
+            return true;
+        }
+        let len = fs::metadata(file).map(|metadata| metadata.len()).unwrap_or(0);
+        if len > 0 && last_len == Some(len) {
+            return false;
+        }
+        last_len = Some(len);
+        thread::sleep(DEBOUNCE);
+    }
+    false
+}
+
+fn process_image(file: &str, root: &Path, args: &Args, options: &OCROptions, cache: Option<&Cache>, strip_stdout: bool, exported: &mut HashSet<PathBuf>) {
+    let mut ocr_results = match get_ocr_results(file, args.frame, options, cache, Duration::from_secs(args.timeout)) {
+        Ok(ocr_results) => ocr_results,
+        Err(e) => {
+            eprintln!("{}: {}", file, e);
+            return;
+        }
+    };
+    report_success(args, file, &ocr_results);
+
+    if args.ocr {
+        if args.strip_control_chars {
+            ocr_results.iter_mut().for_each(OCRResult::strip_control_chars);
+        }
+        if let Some(ndjson) = &args.ndjson {
+            write_output(&render_output(file, &ocr_results, OutputFormat::Jsonl), Some(ndjson));
+        }
+        let input = InputFile::new(file.to_string(), Some(root.to_path_buf()));
+        if let Some(stem) = input.export_stem(args.output_dir.as_deref().map(Path::new)) {
+            export_results(args, file, &stem, &ocr_results);
+            // Event paths are canonical, so the exported file is too (text files split by language aren't inputs)
+            if let Ok(path) = fs::canonicalize(format!("{}.{}", stem, args.format.extension())) {
+                exported.insert(path);
+            }
+        }
+    } else {
+        if strip_stdout {
+            ocr_results.iter_mut().for_each(OCRResult::strip_control_chars);
+        }
+        write_output(&render_output(file, &ocr_results, args.format), args.append_to.as_deref());
+    }
+}